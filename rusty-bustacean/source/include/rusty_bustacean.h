@@ -0,0 +1,28 @@
+#ifndef RUSTY_BUSTACEAN_H
+#define RUSTY_BUSTACEAN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define VM_CONTINUE 0
+#define VM_HALTED 1
+#define VM_NEEDS_INPUT 2
+#define VM_ERROR -1
+
+typedef struct VmHandle VmHandle;
+
+VmHandle *vm_new(void);
+
+void vm_free(VmHandle *handle);
+
+int32_t vm_load(VmHandle *handle, const uint8_t *code, size_t len);
+
+int32_t vm_step(VmHandle *handle);
+
+size_t vm_get_reg(const VmHandle *handle, uint8_t reg);
+
+int32_t vm_feed_input(VmHandle *handle, const uint8_t *input, size_t len);
+
+size_t vm_read_output(VmHandle *handle, uint8_t *buf, size_t cap);
+
+#endif
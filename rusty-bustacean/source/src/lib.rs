@@ -0,0 +1,3936 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(not(feature = "std"))]
+use alloc::{format, string::ToString, vec, vec::Vec};
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::sync::Arc;
+use core::convert::TryInto;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::sync::{Mutex as Lock, MutexGuard as LockGuard};
+#[cfg(not(feature = "std"))]
+use core::cell::UnsafeCell;
+#[cfg(not(feature = "std"))]
+use core::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+#[cfg(feature = "std")]
+pub use std::io::{ErrorKind as IoErrorKind, Read, Write};
+
+// Without std, hosts plug in I/O through these minimal stand-ins for std::io
+#[cfg(not(feature = "std"))]
+pub trait Read {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), IoErrorKind>;
+}
+
+#[cfg(not(feature = "std"))]
+pub trait Write {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), IoErrorKind>;
+
+    fn flush(&mut self) -> Result<(), IoErrorKind> {
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoErrorKind {
+    UnexpectedEof,
+    Other
+}
+
+#[cfg(not(feature = "std"))]
+impl core::fmt::Display for IoErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            IoErrorKind::UnexpectedEof => write!(f, "unexpected end of file"),
+            IoErrorKind::Other => write!(f, "other error"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VMReg {
+    A,
+    B,
+    C,
+    D,
+    IP,
+    SP,
+    BP,
+    Flags,
+    R4,
+    R5,
+    R6,
+    R7,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15
+}
+
+// A-D double as the first four registers of the numbered bank
+impl VMReg {
+    pub const R0: VMReg = VMReg::A;
+    pub const R1: VMReg = VMReg::B;
+    pub const R2: VMReg = VMReg::C;
+    pub const R3: VMReg = VMReg::D;
+
+    // Index into the general-purpose bank, None for IP/SP/BP/Flags
+    fn gpr_index(&self) -> Option<usize> {
+        match *self as u8 {
+            n @ 0..=3 => Some(n as usize),
+            n @ 8..=19 => Some(n as usize - 4),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for VMReg {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self.gpr_index() {
+            Some(i) if i >= 4 => write!(f, "r{}", i),
+            _ => write!(f, "{}", format!("{:?}", self).to_lowercase()),
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VMFReg {
+    F0,
+    F1,
+    F2,
+    F3
+}
+
+impl core::fmt::Display for VMFReg {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "f{}", *self as u8)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VMOp {
+    PushI(usize),
+    PushR(VMReg),
+    Pop(VMReg),
+    Peek(VMReg, usize),
+    Poke(VMReg, usize),
+    Dup,
+    Over,
+    Rot,
+    Drop,
+    Pusha,
+    Popa,
+    Enter(usize),
+    Leave,
+    LdLocal(VMReg, usize),
+    StLocal(VMReg, usize),
+    VAdd(usize),
+    VXor(usize),
+    VShl(usize),
+    Mov(VMReg, VMReg),
+    MovI(VMReg, usize),
+    Xchg(VMReg, VMReg),
+    Cmov(VMReg, VMReg),
+    Select(VMReg, VMReg, VMReg),
+    Load(VMReg, VMReg),
+    Store(VMReg, VMReg),
+    LoadW(VMReg, VMReg),
+    StoreW(VMReg, VMReg),
+    Memcmp(VMReg, VMReg, VMReg),
+    Memcpy(VMReg, VMReg, VMReg),
+    Memset(VMReg, VMReg, VMReg),
+    Alloc(VMReg),
+    Free(VMReg),
+    Crc32(VMReg, VMReg, VMReg),
+    Sha256(VMReg, VMReg, VMReg),
+    XteaRound(VMReg, VMReg, VMReg),
+    Rc4Init(VMReg, VMReg),
+    Rc4Byte(VMReg),
+    ModExp(VMReg, VMReg, VMReg),
+    FAdd(VMFReg, VMFReg),
+    FSub(VMFReg, VMFReg),
+    FMul(VMFReg, VMFReg),
+    FDiv(VMFReg, VMFReg),
+    FCmp(VMFReg, VMFReg),
+    ItoF(VMFReg, VMReg),
+    FtoI(VMReg, VMFReg),
+    Add(VMReg, VMReg),
+    AddS(VMReg, VMReg),
+    Sub(VMReg, VMReg),
+    SubS(VMReg, VMReg),
+    Inc(VMReg),
+    Dec(VMReg),
+    Mul(VMReg, VMReg),
+    MulS(VMReg, VMReg),
+    MulH(VMReg, VMReg),
+    Mac(VMReg, VMReg, VMReg),
+    MacW(VMReg, VMReg, VMReg),
+    Add128(VMReg, VMReg, VMReg, VMReg),
+    Mul128(VMReg, VMReg, VMReg, VMReg),
+    Div(VMReg, VMReg),
+    Mod(VMReg, VMReg),
+    IDiv(VMReg, VMReg),
+    IMod(VMReg, VMReg),
+    Xor(VMReg, VMReg),
+    And(VMReg, VMReg),
+    Or(VMReg, VMReg),
+    Shl(VMReg, VMReg),
+    Shr(VMReg, VMReg),
+    Rol(VMReg, VMReg),
+    Ror(VMReg, VMReg),
+    Not(VMReg),
+    Neg(VMReg),
+    Popcnt(VMReg),
+    Clz(VMReg),
+    Ctz(VMReg),
+    Bt(VMReg, VMReg),
+    Bts(VMReg, VMReg),
+    Btc(VMReg, VMReg),
+    Bfe(VMReg, VMReg, usize, usize),
+    Bfi(VMReg, VMReg, usize, usize),
+    Inp(VMReg),
+    InpLine(VMReg, VMReg),
+    InpInt(VMReg),
+    InpN(VMReg, usize),
+    Eq(VMReg, VMReg),
+    Gt(VMReg, VMReg),
+    Lt(VMReg, VMReg),
+    SGt(VMReg, VMReg),
+    SLt(VMReg, VMReg),
+    Cmp(VMReg, VMReg),
+    Jmp(usize),
+    JmpRel(isize),
+    JmpR(VMReg),
+    Je(usize),
+    Jne(usize),
+    Jg(usize),
+    Jge(usize),
+    Jl(usize),
+    Jle(usize),
+    Call(usize),
+    CallR(VMReg),
+    Ret,
+    Int(usize),
+    Iret,
+    Print(VMReg),
+    Rand(VMReg),
+    Time(VMReg),
+    Sleep(VMReg),
+    Syscall(usize),
+    Ext(u16),
+    Spawn(VMReg, usize),
+    Yield,
+    Join(VMReg),
+    Cas(VMReg, VMReg, VMReg),
+    Getenv(VMReg, VMReg, VMReg),
+    FRead(VMReg, VMReg, VMReg),
+    PrintInt(VMReg),
+    PrintHex(VMReg),
+    PrintStr(VMReg),
+    Brk,
+    Nop,
+    Halt,
+    HaltCode(VMReg)
+}
+
+// Assembler syntax: lowercase mnemonic, then operands separated by commas with immediates in hex
+impl core::fmt::Display for VMOp {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            VMOp::PushI(a) => write!(f, "pushi {:#x}", a),
+            VMOp::PushR(a) => write!(f, "pushr {}", a),
+            VMOp::Pop(a) => write!(f, "pop {}", a),
+            VMOp::Peek(a, b) => write!(f, "peek {}, {:#x}", a, b),
+            VMOp::Poke(a, b) => write!(f, "poke {}, {:#x}", a, b),
+            VMOp::Dup => write!(f, "dup"),
+            VMOp::Over => write!(f, "over"),
+            VMOp::Rot => write!(f, "rot"),
+            VMOp::Drop => write!(f, "drop"),
+            VMOp::Pusha => write!(f, "pusha"),
+            VMOp::Popa => write!(f, "popa"),
+            VMOp::Enter(a) => write!(f, "enter {:#x}", a),
+            VMOp::Leave => write!(f, "leave"),
+            VMOp::LdLocal(a, b) => write!(f, "ldlocal {}, {:#x}", a, b),
+            VMOp::StLocal(a, b) => write!(f, "stlocal {}, {:#x}", a, b),
+            VMOp::VAdd(a) => write!(f, "vadd {:#x}", a),
+            VMOp::VXor(a) => write!(f, "vxor {:#x}", a),
+            VMOp::VShl(a) => write!(f, "vshl {:#x}", a),
+            VMOp::Mov(a, b) => write!(f, "mov {}, {}", a, b),
+            VMOp::MovI(a, b) => write!(f, "movi {}, {:#x}", a, b),
+            VMOp::Xchg(a, b) => write!(f, "xchg {}, {}", a, b),
+            VMOp::Cmov(a, b) => write!(f, "cmov {}, {}", a, b),
+            VMOp::Select(a, b, c) => write!(f, "select {}, {}, {}", a, b, c),
+            VMOp::Load(a, b) => write!(f, "load {}, {}", a, b),
+            VMOp::Store(a, b) => write!(f, "store {}, {}", a, b),
+            VMOp::LoadW(a, b) => write!(f, "loadw {}, {}", a, b),
+            VMOp::StoreW(a, b) => write!(f, "storew {}, {}", a, b),
+            VMOp::Memcmp(a, b, c) => write!(f, "memcmp {}, {}, {}", a, b, c),
+            VMOp::Memcpy(a, b, c) => write!(f, "memcpy {}, {}, {}", a, b, c),
+            VMOp::Memset(a, b, c) => write!(f, "memset {}, {}, {}", a, b, c),
+            VMOp::Alloc(a) => write!(f, "alloc {}", a),
+            VMOp::Free(a) => write!(f, "free {}", a),
+            VMOp::Crc32(a, b, c) => write!(f, "crc32 {}, {}, {}", a, b, c),
+            VMOp::Sha256(a, b, c) => write!(f, "sha256 {}, {}, {}", a, b, c),
+            VMOp::XteaRound(a, b, c) => write!(f, "xtearound {}, {}, {}", a, b, c),
+            VMOp::Rc4Init(a, b) => write!(f, "rc4init {}, {}", a, b),
+            VMOp::Rc4Byte(a) => write!(f, "rc4byte {}", a),
+            VMOp::ModExp(a, b, c) => write!(f, "modexp {}, {}, {}", a, b, c),
+            VMOp::FAdd(a, b) => write!(f, "fadd {}, {}", a, b),
+            VMOp::FSub(a, b) => write!(f, "fsub {}, {}", a, b),
+            VMOp::FMul(a, b) => write!(f, "fmul {}, {}", a, b),
+            VMOp::FDiv(a, b) => write!(f, "fdiv {}, {}", a, b),
+            VMOp::FCmp(a, b) => write!(f, "fcmp {}, {}", a, b),
+            VMOp::ItoF(a, b) => write!(f, "itof {}, {}", a, b),
+            VMOp::FtoI(a, b) => write!(f, "ftoi {}, {}", a, b),
+            VMOp::Add(a, b) => write!(f, "add {}, {}", a, b),
+            VMOp::AddS(a, b) => write!(f, "adds {}, {}", a, b),
+            VMOp::Sub(a, b) => write!(f, "sub {}, {}", a, b),
+            VMOp::SubS(a, b) => write!(f, "subs {}, {}", a, b),
+            VMOp::Inc(a) => write!(f, "inc {}", a),
+            VMOp::Dec(a) => write!(f, "dec {}", a),
+            VMOp::Mul(a, b) => write!(f, "mul {}, {}", a, b),
+            VMOp::MulS(a, b) => write!(f, "muls {}, {}", a, b),
+            VMOp::MulH(a, b) => write!(f, "mulh {}, {}", a, b),
+            VMOp::Mac(a, b, c) => write!(f, "mac {}, {}, {}", a, b, c),
+            VMOp::MacW(a, b, c) => write!(f, "macw {}, {}, {}", a, b, c),
+            VMOp::Add128(a, b, c, d) => write!(f, "add128 {}, {}, {}, {}", a, b, c, d),
+            VMOp::Mul128(a, b, c, d) => write!(f, "mul128 {}, {}, {}, {}", a, b, c, d),
+            VMOp::Div(a, b) => write!(f, "div {}, {}", a, b),
+            VMOp::Mod(a, b) => write!(f, "mod {}, {}", a, b),
+            VMOp::IDiv(a, b) => write!(f, "idiv {}, {}", a, b),
+            VMOp::IMod(a, b) => write!(f, "imod {}, {}", a, b),
+            VMOp::Xor(a, b) => write!(f, "xor {}, {}", a, b),
+            VMOp::And(a, b) => write!(f, "and {}, {}", a, b),
+            VMOp::Or(a, b) => write!(f, "or {}, {}", a, b),
+            VMOp::Shl(a, b) => write!(f, "shl {}, {}", a, b),
+            VMOp::Shr(a, b) => write!(f, "shr {}, {}", a, b),
+            VMOp::Rol(a, b) => write!(f, "rol {}, {}", a, b),
+            VMOp::Ror(a, b) => write!(f, "ror {}, {}", a, b),
+            VMOp::Not(a) => write!(f, "not {}", a),
+            VMOp::Neg(a) => write!(f, "neg {}", a),
+            VMOp::Popcnt(a) => write!(f, "popcnt {}", a),
+            VMOp::Clz(a) => write!(f, "clz {}", a),
+            VMOp::Ctz(a) => write!(f, "ctz {}", a),
+            VMOp::Bt(a, b) => write!(f, "bt {}, {}", a, b),
+            VMOp::Bts(a, b) => write!(f, "bts {}, {}", a, b),
+            VMOp::Btc(a, b) => write!(f, "btc {}, {}", a, b),
+            VMOp::Bfe(a, b, c, d) => write!(f, "bfe {}, {}, {:#x}, {:#x}", a, b, c, d),
+            VMOp::Bfi(a, b, c, d) => write!(f, "bfi {}, {}, {:#x}, {:#x}", a, b, c, d),
+            VMOp::Inp(a) => write!(f, "inp {}", a),
+            VMOp::InpLine(a, b) => write!(f, "inpline {}, {}", a, b),
+            VMOp::InpInt(a) => write!(f, "inpint {}", a),
+            VMOp::InpN(a, b) => write!(f, "inpn {}, {:#x}", a, b),
+            VMOp::Eq(a, b) => write!(f, "eq {}, {}", a, b),
+            VMOp::Gt(a, b) => write!(f, "gt {}, {}", a, b),
+            VMOp::Lt(a, b) => write!(f, "lt {}, {}", a, b),
+            VMOp::SGt(a, b) => write!(f, "sgt {}, {}", a, b),
+            VMOp::SLt(a, b) => write!(f, "slt {}, {}", a, b),
+            VMOp::Cmp(a, b) => write!(f, "cmp {}, {}", a, b),
+            VMOp::Jmp(a) => write!(f, "jmp {:#x}", a),
+            VMOp::JmpRel(a) => write!(f, "jmprel {}", a),
+            VMOp::JmpR(a) => write!(f, "jmpr {}", a),
+            VMOp::Je(a) => write!(f, "je {:#x}", a),
+            VMOp::Jne(a) => write!(f, "jne {:#x}", a),
+            VMOp::Jg(a) => write!(f, "jg {:#x}", a),
+            VMOp::Jge(a) => write!(f, "jge {:#x}", a),
+            VMOp::Jl(a) => write!(f, "jl {:#x}", a),
+            VMOp::Jle(a) => write!(f, "jle {:#x}", a),
+            VMOp::Call(a) => write!(f, "call {:#x}", a),
+            VMOp::CallR(a) => write!(f, "callr {}", a),
+            VMOp::Ret => write!(f, "ret"),
+            VMOp::Int(a) => write!(f, "int {:#x}", a),
+            VMOp::Iret => write!(f, "iret"),
+            VMOp::Print(a) => write!(f, "print {}", a),
+            VMOp::Rand(a) => write!(f, "rand {}", a),
+            VMOp::Time(a) => write!(f, "time {}", a),
+            VMOp::Sleep(a) => write!(f, "sleep {}", a),
+            VMOp::Syscall(a) => write!(f, "syscall {:#x}", a),
+            VMOp::Ext(a) => write!(f, "ext {:#x}", a),
+            VMOp::Spawn(a, b) => write!(f, "spawn {}, {:#x}", a, b),
+            VMOp::Yield => write!(f, "yield"),
+            VMOp::Join(a) => write!(f, "join {}", a),
+            VMOp::Cas(a, b, c) => write!(f, "cas {}, {}, {}", a, b, c),
+            VMOp::Getenv(a, b, c) => write!(f, "getenv {}, {}, {}", a, b, c),
+            VMOp::FRead(a, b, c) => write!(f, "fread {}, {}, {}", a, b, c),
+            VMOp::PrintInt(a) => write!(f, "printint {}", a),
+            VMOp::PrintHex(a) => write!(f, "printhex {}", a),
+            VMOp::PrintStr(a) => write!(f, "printstr {}", a),
+            VMOp::Brk => write!(f, "brk"),
+            VMOp::Nop => write!(f, "nop"),
+            VMOp::Halt => write!(f, "halt"),
+            VMOp::HaltCode(a) => write!(f, "haltcode {}", a),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endianness {
+    Big,
+    Little
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowMode {
+    Wrapping,
+    Checked,
+    // Wraps and sets the overflow flag, then vectors to the handler like an Int if one is given
+    Trap(Option<usize>)
+}
+
+#[derive(Debug)]
+pub enum VMError {
+    StackOverflow,
+    StackUnderflow,
+    OutOfMemory,
+    InvalidFree(usize),
+    ArithmeticOverflow,
+    MemoryOutOfBounds(usize),
+    ProtectionFault(usize),
+    InvalidInstruction(usize),
+    OutOfFuel,
+    InvalidThread(usize),
+    StackCorruption(usize),
+    DeadlineExceeded,
+    Nondeterministic(usize),
+    DivisionByZero,
+    IpOutOfBounds(usize),
+    UnknownInterrupt(usize),
+    UnknownSyscall(usize),
+    AccessDenied(usize),
+    UnsupportedWordSize(u32),
+    InvalidSleepScale,
+    Io(IoErrorKind),
+    EndOfInput
+}
+
+impl core::fmt::Display for VMError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            VMError::StackOverflow => write!(f, "stack overflow"),
+            VMError::StackUnderflow => write!(f, "stack underflow"),
+            VMError::OutOfMemory => write!(f, "heap exhausted"),
+            VMError::InvalidFree(addr) => write!(f, "free of unallocated address {:#x}", addr),
+            VMError::ArithmeticOverflow => write!(f, "arithmetic overflow"),
+            VMError::MemoryOutOfBounds(addr) => write!(f, "memory access out of bounds at {:#x}", addr),
+            VMError::ProtectionFault(addr) => write!(f, "memory protection fault at {:#x}", addr),
+            VMError::InvalidInstruction(ip) => write!(f, "invalid instruction at {:#x}", ip),
+            VMError::OutOfFuel => write!(f, "instruction limit exceeded"),
+            VMError::InvalidThread(id) => write!(f, "cannot join thread {}", id),
+            VMError::StackCorruption(slot) => write!(f, "stack guard slot {} corrupted", slot),
+            VMError::DeadlineExceeded => write!(f, "wall-clock deadline exceeded"),
+            VMError::Nondeterministic(ip) => write!(f, "nondeterministic instruction at {:#x} refused", ip),
+            VMError::DivisionByZero => write!(f, "division by zero"),
+            VMError::IpOutOfBounds(ip) => write!(f, "instruction pointer {:#x} out of bounds", ip),
+            VMError::UnknownInterrupt(num) => write!(f, "no handler for interrupt {:#x}", num),
+            VMError::UnknownSyscall(num) => write!(f, "unknown syscall {:#x}", num),
+            VMError::AccessDenied(ip) => write!(f, "host access denied at {:#x}", ip),
+            VMError::UnsupportedWordSize(bits) => write!(f, "unsupported word size {}", bits),
+            VMError::InvalidSleepScale => write!(f, "sleep scale must be finite and non-negative"),
+            VMError::Io(kind) => write!(f, "host I/O failed: {}", kind),
+            VMError::EndOfInput => write!(f, "unexpected end of input"),
+        }
+    }
+}
+
+impl core::error::Error for VMError {}
+
+// How a program that ran to completion ended, carrying the HaltCode value (0 for Halt)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExitStatus(pub usize);
+
+impl ExitStatus {
+    pub fn code(&self) -> usize {
+        self.0
+    }
+
+    pub fn success(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    Continue,
+    Halted(ExitStatus),
+    // The input instruction at ip ran out of buffered input, it is retried by the next step
+    NeedsInput
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TraceEntry {
+    pub ip: usize,
+    pub op: VMOp,
+    pub regs_before: [usize; 16],
+    pub regs_after: [usize; 16]
+}
+
+// Steps the VM as it is consumed, ending after the program halts or faults, or before an
+// instruction that is waiting for nonblocking input (a fresh trace resumes once it's pushed)
+pub struct Trace<'a> {
+    vm: &'a mut VM,
+    done: bool
+}
+
+impl Iterator for Trace<'_> {
+    type Item = Result<TraceEntry, VMError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.vm.is_halted {
+            return None;
+        }
+        let (ip, regs_before) = (self.vm.ip, self.vm.regs);
+        let entry = self.vm.fetch().and_then(|op| {
+            let outcome = self.vm.step()?;
+            Ok((outcome, TraceEntry { ip, op, regs_before, regs_after: self.vm.regs }))
+        });
+        match entry {
+            Ok((StepOutcome::NeedsInput, _)) => {
+                self.done = true;
+                None
+            },
+            Ok((_, entry)) => Some(Ok(entry)),
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+// First-fit free list over a region of linear memory, metadata is kept host-side
+#[derive(Clone)]
+struct Heap {
+    region: (usize, usize),
+    free: Vec<(usize, usize)>,
+    allocated: BTreeMap<usize, usize>
+}
+
+impl Heap {
+    fn new(start: usize, len: usize) -> Heap {
+        Heap {
+            region: (start, len),
+            free: if len > 0 { vec![(start, len)] } else { Vec::new() },
+            allocated: BTreeMap::new()
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = Heap::new(self.region.0, self.region.1);
+    }
+
+    fn alloc(&mut self, size: usize) -> Option<usize> {
+        let size = size.max(1);
+        let index = self.free.iter().position(|(_, len)| *len >= size)?;
+        let (addr, len) = self.free[index];
+        if len == size {
+            self.free.remove(index);
+        }
+        else {
+            self.free[index] = (addr + size, len - size);
+        }
+        self.allocated.insert(addr, size);
+        Some(addr)
+    }
+
+    fn free(&mut self, addr: usize) -> bool {
+        let size = match self.allocated.remove(&addr) {
+            Some(size) => size,
+            None => return false,
+        };
+        let index = self.free.partition_point(|(start, _)| *start < addr);
+        self.free.insert(index, (addr, size));
+        // Coalesce with the following and preceding blocks
+        if index + 1 < self.free.len() && addr + size == self.free[index + 1].0 {
+            self.free[index].1 += self.free.remove(index + 1).1;
+        }
+        if index > 0 && self.free[index - 1].0 + self.free[index - 1].1 == addr {
+            self.free[index - 1].1 += self.free.remove(index).1;
+        }
+        true
+    }
+}
+
+#[derive(Clone)]
+struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Rc4 {
+        let mut s = [0u8; 256];
+        for (i, val) in s.iter_mut().enumerate() {
+            *val = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Rc4 { s, i: 0, j: 0 }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        self.i = self.i.wrapping_add(1);
+        self.j = self.j.wrapping_add(self.s[self.i as usize]);
+        self.s.swap(self.i as usize, self.j as usize);
+        self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize]
+    }
+}
+
+// Host-defined instructions, Ext(op) hands the whole machine to the registered handler
+pub trait ExtOpHandler {
+    fn exec(&mut self, op: u16, vm: &mut VM) -> Result<(), VMError>;
+}
+
+// Lifecycle notifications, every method defaults to doing nothing
+pub trait Observer {
+    fn on_start(&mut self) {}
+    fn on_halt(&mut self, _status: ExitStatus) {}
+    fn on_input(&mut self, _byte: u8) {}
+    fn on_output(&mut self, _bytes: &[u8]) {}
+    // from is the ip of the Call/Ret, to is where execution continues
+    fn on_call(&mut self, _from: usize, _to: usize) {}
+    fn on_return(&mut self, _from: usize, _to: usize) {}
+    fn on_fault(&mut self, _err: &VMError) {}
+}
+
+// Host callbacks are shared between forks of a VM, and must be Send so the VM can move threads
+// (without std there is no Mutex, so they sit behind a spin lock instead)
+type TimeSource = Arc<Lock<dyn FnMut() -> u64 + Send>>;
+type SyscallHandler = Arc<Lock<dyn FnMut(&mut VM) + Send>>;
+type InputHandle = Arc<Lock<dyn Read + Send>>;
+type OutputHandle = Arc<Lock<dyn Write + Send>>;
+type IoReadHandler = Arc<Lock<dyn FnMut(usize) -> u8 + Send>>;
+type IoWriteHandler = Arc<Lock<dyn FnMut(usize, u8) + Send>>;
+type BreakpointHandler = Arc<Lock<dyn FnMut(&VMState) + Send>>;
+type SharedObserver = Arc<Lock<dyn Observer + Send>>;
+type ExtHandler = Arc<Lock<dyn ExtOpHandler + Send>>;
+type InstructionHook = Arc<Lock<dyn FnMut(usize, &VMOp, &VM) + Send>>;
+
+// Handlers receive the offset into the region
+#[derive(Clone)]
+struct IoRegion {
+    start: usize,
+    len: usize,
+    read: IoReadHandler,
+    write: IoWriteHandler
+}
+
+impl IoRegion {
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr - self.start < self.len
+    }
+}
+
+// Per-thread machine state, memory is shared by every thread
+#[derive(Clone, Default)]
+struct ThreadContext {
+    id: usize,
+    regs: [usize; 16],
+    ip: usize,
+    sp: usize,
+    bp: usize,
+    flags: usize,
+    fregs: [f64; 4],
+    stack: Vec<usize>,
+    shadow_stack: Option<Vec<usize>>
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VMState {
+    pub regs: [usize; 16],
+    pub ip: usize,
+    pub sp: usize,
+    pub bp: usize,
+    pub flags: usize,
+    pub fregs: [f64; 4],
+    // Live slots bottom first, as stack() returns them, out of stack_capacity
+    pub stack: Vec<usize>,
+    pub stack_capacity: usize,
+    pub shadow_stack: Option<Vec<usize>>,
+    pub memory: Vec<u8>
+}
+
+#[derive(Clone)]
+pub struct VM {
+    regs: [usize; 16],
+    ip: usize,
+    sp: usize,
+    bp: usize,
+    flags: usize,
+    fregs: [f64; 4],
+    is_halted: bool,
+    exit_code: usize,
+    fuel: Option<usize>,
+    steps: usize,
+    step_limit: Option<usize>,
+    deadline: Option<Duration>,
+    timer: Option<(usize, usize)>,
+    timer_ticks: usize,
+    endianness: Endianness,
+    word_bits: u32,
+    overflow_mode: OverflowMode,
+    // What reset rewinds the RNG and input to
+    seed: u64,
+    rng_state: u64,
+    #[cfg(feature = "std")]
+    start_time: std::time::Instant,
+    time_source: Option<TimeSource>,
+    sleep_scale: f64,
+    deterministic: bool,
+    virtual_time: u64,
+    scripted_input: Option<VecDeque<u8>>,
+    initial_input: Option<Vec<u8>>,
+    nonblocking_input: bool,
+    input_starved: bool,
+    retrying_input: bool,
+    consumed_input: Vec<u8>,
+    reader: Option<InputHandle>,
+    writer: Option<OutputHandle>,
+    captured_output: Option<Vec<u8>>,
+    syscalls: BTreeMap<usize, SyscallHandler>,
+    breakpoint_handler: Option<BreakpointHandler>,
+    ext_handler: Option<ExtHandler>,
+    observers: Vec<SharedObserver>,
+    pre_hook: Option<InstructionHook>,
+    post_hook: Option<InstructionHook>,
+    interrupt_vectors: BTreeMap<usize, usize>,
+    rc4: Option<Rc4>,
+    allow_getenv: bool,
+    #[cfg(feature = "std")]
+    readable_files: Vec<std::path::PathBuf>,
+    stack: Vec<usize>,
+    max_stack_size: usize,
+    check_stack_guards: bool,
+    shadow_stack: Option<Vec<usize>>,
+    thread_id: usize,
+    next_thread_id: usize,
+    threads: VecDeque<ThreadContext>,
+    finished_threads: BTreeSet<usize>,
+    memory: Arc<Vec<u8>>,
+    page_flags: Vec<u8>,
+    io_regions: Vec<IoRegion>,
+    data: Vec<u8>,
+    heap: Heap,
+    code_base: Option<usize>,
+    code: Vec<VMOp>
+}
+
+const FLAG_ZERO: usize = 1 << 0;
+const FLAG_CARRY: usize = 1 << 1;
+const FLAG_OVERFLOW: usize = 1 << 2;
+
+pub const PAGE_SIZE: usize = 0x100;
+pub const PROT_READ: u8 = 1 << 0;
+pub const PROT_WRITE: u8 = 1 << 1;
+pub const PROT_EXEC: u8 = 1 << 2;
+
+// Code loaded into memory gets one fixed-size slot per instruction so ip stays an index
+const INST_SIZE: usize = 0x18;
+
+// The stack allocation carries one canary slot below the first and above the last data slot
+const STACK_CANARY: usize = 0x5afe_57ac;
+
+// Instructions between wall-clock deadline checks
+const DEADLINE_CHECK_INTERVAL: usize = 0x400;
+
+const DEFAULT_STACK_SIZE: usize = 0xff;
+const DEFAULT_MEMORY_SIZE: usize = 0x1000;
+
+impl VM {
+    pub fn new(code: Vec<VMOp>) -> VM {
+        VM::with_sizes(code, DEFAULT_STACK_SIZE, DEFAULT_MEMORY_SIZE)
+    }
+
+    pub fn with_stack_size(code: Vec<VMOp>, stack_size: usize) -> VM {
+        VM::with_sizes(code, stack_size, DEFAULT_MEMORY_SIZE)
+    }
+
+    pub fn with_memory_size(code: Vec<VMOp>, memory_size: usize) -> VM {
+        VM::with_sizes(code, DEFAULT_STACK_SIZE, memory_size)
+    }
+
+    // The data image is copied to address 0, memory is sized so it stays clear of the heap
+    pub fn with_data(code: Vec<VMOp>, data: Vec<u8>) -> VM {
+        VMBuilder::new().data(data).build(code).expect("memory is sized to fit the data image")
+    }
+
+    fn with_sizes(code: Vec<VMOp>, stack_size: usize, memory_size: usize) -> VM {
+        let seed = initial_seed();
+        VM {
+            regs: [0; 16],
+            ip: 0,
+            sp: 0,
+            bp: 0,
+            flags: 0,
+            fregs: [0.0; 4],
+            is_halted: false,
+            exit_code: 0,
+            fuel: None,
+            steps: 0,
+            step_limit: None,
+            deadline: None,
+            timer: None,
+            timer_ticks: 0,
+            endianness: Endianness::Big,
+            word_bits: usize::BITS,
+            overflow_mode: OverflowMode::Wrapping,
+            seed,
+            rng_state: seed,
+            #[cfg(feature = "std")]
+            start_time: std::time::Instant::now(),
+            time_source: None,
+            sleep_scale: 1.0,
+            deterministic: false,
+            virtual_time: 0,
+            scripted_input: None,
+            initial_input: None,
+            nonblocking_input: false,
+            input_starved: false,
+            retrying_input: false,
+            consumed_input: Vec::new(),
+            reader: default_reader(),
+            writer: default_writer(),
+            captured_output: None,
+            syscalls: BTreeMap::new(),
+            breakpoint_handler: None,
+            ext_handler: None,
+            observers: Vec::new(),
+            pre_hook: None,
+            post_hook: None,
+            interrupt_vectors: BTreeMap::new(),
+            rc4: None,
+            allow_getenv: false,
+            #[cfg(feature = "std")]
+            readable_files: Vec::new(),
+            stack: guarded_stack(stack_size),
+            max_stack_size: stack_size,
+            check_stack_guards: cfg!(debug_assertions),
+            shadow_stack: None,
+            thread_id: 0,
+            next_thread_id: 1,
+            threads: VecDeque::new(),
+            finished_threads: BTreeSet::new(),
+            memory: Arc::new(vec![0; memory_size]),
+            page_flags: vec![PROT_READ | PROT_WRITE; memory_size.div_ceil(PAGE_SIZE)],
+            io_regions: Vec::new(),
+            data: Vec::new(),
+            heap: Heap::new(memory_size / 2, memory_size - memory_size / 2),
+            code_base: None,
+            code
+        }
+    }
+
+    // Byte order used by LoadW/StoreW and InpN
+    pub fn set_endianness(&mut self, endianness: Endianness) {
+        self.endianness = endianness;
+    }
+
+    // General registers are truncated to this many bits after every write, so bytecode
+    // behaves the same regardless of the host's usize
+    pub fn set_word_size(&mut self, bits: u32) -> Result<(), VMError> {
+        if !matches!(bits, 8 | 16 | 32 | 64) || bits > usize::BITS {
+            return Err(VMError::UnsupportedWordSize(bits));
+        }
+        self.word_bits = bits;
+        let mask = bit_mask(bits as usize);
+        for reg in self.regs.iter_mut() {
+            *reg &= mask;
+        }
+        Ok(())
+    }
+
+    pub fn set_overflow_mode(&mut self, mode: OverflowMode) {
+        self.overflow_mode = mode;
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng_state = seed;
+    }
+
+    // Overrides the millisecond clock read by Time, e.g. to fake it in tests
+    pub fn set_time_source<F: FnMut() -> u64 + Send + 'static>(&mut self, source: F) {
+        self.time_source = Some(Arc::new(Lock::new(source)));
+    }
+
+    // Aborts run with DeadlineExceeded once this much real time has passed since it started
+    pub fn set_deadline(&mut self, deadline: Duration) {
+        self.deadline = Some(deadline);
+    }
+
+    pub fn clear_deadline(&mut self) {
+        self.deadline = None;
+    }
+
+    // Makes runs bit-identical: Rand is seeded, Time reads a virtual clock that only Sleep
+    // advances, input comes from set_input (EOF if none) and Getenv/FRead are refused
+    pub fn set_deterministic(&mut self, seed: u64) {
+        self.deterministic = true;
+        self.set_seed(seed);
+        self.virtual_time = 0;
+    }
+
+    // Feeds Inp and friends from this buffer instead of stdin
+    pub fn set_input<I: Into<Vec<u8>>>(&mut self, input: I) {
+        let input = input.into();
+        self.scripted_input = Some(input.iter().copied().collect());
+        self.initial_input = Some(input);
+    }
+
+    // Instead of blocking on the reader, step returns NeedsInput once the buffer filled by
+    // push_input runs dry, so a host can wait for more input however it likes
+    pub fn set_nonblocking_input(&mut self, enabled: bool) {
+        self.nonblocking_input = enabled;
+        if enabled && self.scripted_input.is_none() {
+            self.scripted_input = Some(VecDeque::new());
+        }
+    }
+
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.scripted_input.get_or_insert_with(VecDeque::new).extend(input);
+    }
+
+    // Source for Inp and friends, stdin by default (nothing without std)
+    pub fn set_reader<R: Read + Send + 'static>(&mut self, reader: R) {
+        self.reader = Some(Arc::new(Lock::new(reader)));
+    }
+
+    // Sink for Print and friends, stdout by default (discarded without std), flushed whenever
+    // the program halts
+    pub fn set_writer<W: Write + Send + 'static>(&mut self, writer: W) {
+        self.writer = Some(Arc::new(Lock::new(writer)));
+    }
+
+    // Collects Print output inside the VM for take_output instead of sending it to the writer
+    pub fn set_capture_output(&mut self, enabled: bool) {
+        self.captured_output = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    // Everything printed since the last call, empty unless capturing
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.captured_output.as_mut().map(core::mem::take).unwrap_or_default()
+    }
+
+    // Multiplies every Sleep duration, 0.0 disables sleeping entirely
+    pub fn set_sleep_scale(&mut self, scale: f64) -> Result<(), VMError> {
+        if !scale.is_finite() || scale < 0.0 {
+            return Err(VMError::InvalidSleepScale);
+        }
+        self.sleep_scale = scale;
+        Ok(())
+    }
+
+    pub fn register_syscall<F: FnMut(&mut VM) + Send + 'static>(&mut self, num: usize, handler: F) {
+        self.syscalls.insert(num, Arc::new(Lock::new(handler)));
+    }
+
+    pub fn set_interrupt_vector(&mut self, num: usize, addr: usize) {
+        self.interrupt_vectors.insert(num, addr);
+    }
+
+    // Every interval instructions, vectors to handler as if an Int had been executed
+    pub fn set_timer(&mut self, interval: usize, handler: usize) {
+        self.timer = Some((interval, handler));
+        self.timer_ticks = 0;
+    }
+
+    pub fn clear_timer(&mut self) {
+        self.timer = None;
+    }
+
+    // Called with a snapshot of the VM every time a Brk is executed
+    pub fn set_breakpoint_handler<F: FnMut(&VMState) + Send + 'static>(&mut self, handler: F) {
+        self.breakpoint_handler = Some(Arc::new(Lock::new(handler)));
+    }
+
+    // Executes every Ext instruction, which are invalid until one is set
+    pub fn set_ext_handler<H: ExtOpHandler + Send + 'static>(&mut self, handler: H) {
+        self.ext_handler = Some(Arc::new(Lock::new(handler)));
+    }
+
+    pub fn add_observer<O: Observer + Send + 'static>(&mut self, observer: O) {
+        self.observers.push(Arc::new(Lock::new(observer)));
+    }
+
+    // Called with the ip and instruction about to execute
+    pub fn set_pre_hook<F: FnMut(usize, &VMOp, &VM) + Send + 'static>(&mut self, hook: F) {
+        self.pre_hook = Some(Arc::new(Lock::new(hook)));
+    }
+
+    // Called once an instruction has executed, with the ip it was fetched from
+    pub fn set_post_hook<F: FnMut(usize, &VMOp, &VM) + Send + 'static>(&mut self, hook: F) {
+        self.post_hook = Some(Arc::new(Lock::new(hook)));
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.pre_hook = None;
+        self.post_hook = None;
+    }
+
+    // Getenv faults unless the host opts in
+    pub fn set_allow_getenv(&mut self, allow: bool) {
+        self.allow_getenv = allow;
+    }
+
+    // Whitelists a host path for FRead, nothing else can be opened
+    #[cfg(feature = "std")]
+    pub fn allow_file_read<P: Into<std::path::PathBuf>>(&mut self, path: P) {
+        self.readable_files.push(path.into());
+    }
+
+    // Sets the PROT_* flags of every page overlapping the range, pages start out read/write
+    pub fn protect(&mut self, addr: usize, len: usize, flags: u8) -> Result<(), VMError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let last = addr.checked_add(len - 1).ok_or(VMError::MemoryOutOfBounds(addr))?;
+        let (first_page, last_page) = (addr / PAGE_SIZE, last / PAGE_SIZE);
+        for page_flags in self.page_flags.iter_mut().take(last_page + 1).skip(first_page) {
+            *page_flags = flags;
+        }
+        Ok(())
+    }
+
+    // Routes Load/Store in the range to host handlers instead of memory
+    pub fn map_io<R, W>(&mut self, start: usize, len: usize, read: R, write: W)
+    where
+        R: FnMut(usize) -> u8 + Send + 'static,
+        W: FnMut(usize, u8) + Send + 'static
+    {
+        self.io_regions.push(IoRegion {
+            start,
+            len,
+            read: Arc::new(Lock::new(read)),
+            write: Arc::new(Lock::new(write))
+        });
+    }
+
+    // Encodes the program into executable memory at base and fetches from there afterwards,
+    // so Store into the code region rewrites the running program
+    pub fn load_code_into_memory(&mut self, base: usize) -> Result<(), VMError> {
+        let len = self.code.len() * INST_SIZE;
+        self.check_mem_range(base, len, PROT_WRITE)?;
+        self.write_code(base);
+        self.protect(base, len, PROT_READ | PROT_WRITE | PROT_EXEC)?;
+        self.code_base = Some(base);
+        Ok(())
+    }
+
+    // Puts the machine back to its freshly loaded state so the same program can run again,
+    // host configuration (handlers, protections, limits) is kept
+    pub fn reset(&mut self) {
+        self.regs = [0; 16];
+        self.ip = 0;
+        self.sp = 0;
+        self.bp = 0;
+        self.flags = 0;
+        self.fregs = [0.0; 4];
+        self.is_halted = false;
+        self.steps = 0;
+        self.exit_code = 0;
+        self.timer_ticks = 0;
+        self.virtual_time = 0;
+        self.rng_state = self.seed;
+        self.retrying_input = false;
+        // Input pushed since is dropped, only what set_input configured is replayed
+        self.scripted_input = match &self.initial_input {
+            Some(input) => Some(input.iter().copied().collect()),
+            None if self.nonblocking_input => Some(VecDeque::new()),
+            None => None,
+        };
+        self.rc4 = None;
+        let len = self.stack.len() - 2;
+        self.stack = guarded_stack(len);
+        if let Some(shadow_stack) = &mut self.shadow_stack {
+            shadow_stack.clear();
+        }
+        self.thread_id = 0;
+        self.next_thread_id = 1;
+        self.threads.clear();
+        self.finished_threads.clear();
+        let mut memory = vec![0; self.memory.len()];
+        memory[..self.data.len()].copy_from_slice(&self.data);
+        self.memory = Arc::new(memory);
+        if let Some(base) = self.code_base {
+            self.write_code(base);
+        }
+        self.heap.clear();
+    }
+
+    fn write_code(&mut self, base: usize) {
+        for (i, op) in self.code.iter().enumerate() {
+            let mut slot = Vec::with_capacity(INST_SIZE);
+            encode_op(op, &mut slot);
+            slot.resize(INST_SIZE, 0);
+            let addr = base + i * INST_SIZE;
+            Arc::make_mut(&mut self.memory)[addr..addr + INST_SIZE].copy_from_slice(&slot);
+        }
+    }
+
+    // Defaults to the upper half of memory, resetting it forgets every live allocation
+    pub fn set_heap_region(&mut self, start: usize, len: usize) {
+        self.heap = Heap::new(start, len);
+    }
+
+    // Lets the stack grow on demand past its initial size, up to max_size slots
+    pub fn set_max_stack_size(&mut self, max_size: usize) {
+        self.max_stack_size = max_size.max(self.stack.len() - 2);
+    }
+
+    // Verifies both canaries after every push and pop, on by default in debug builds
+    pub fn set_stack_guard_checks(&mut self, enabled: bool) {
+        self.check_stack_guards = enabled;
+    }
+
+    // Keeps Call/Ret return addresses on a separate stack that data pushes can't clobber
+    pub fn set_shadow_stack(&mut self, enabled: bool) {
+        self.shadow_stack = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    pub fn snapshot(&self) -> VMState {
+        VMState {
+            regs: self.regs,
+            ip: self.ip,
+            sp: self.sp,
+            bp: self.bp,
+            flags: self.flags,
+            fregs: self.fregs,
+            stack: self.stack().to_vec(),
+            stack_capacity: self.stack.len() - 2,
+            shadow_stack: self.shadow_stack.clone(),
+            memory: self.memory.to_vec()
+        }
+    }
+
+    // Leaves the VM untouched if the state doesn't fit this machine, the restored program is
+    // runnable again even if it had halted
+    pub fn restore(&mut self, state: &VMState) -> Result<(), VMError> {
+        if state.memory.len() != self.memory.len() {
+            return Err(VMError::MemoryOutOfBounds(state.memory.len()));
+        }
+        if state.stack.len() > state.stack_capacity || state.stack_capacity > self.max_stack_size {
+            return Err(VMError::StackOverflow);
+        }
+        let mask = bit_mask(self.word_bits as usize);
+        self.regs = state.regs.map(|reg| reg & mask);
+        self.ip = state.ip;
+        self.sp = state.sp;
+        self.bp = state.bp;
+        self.flags = state.flags;
+        self.fregs = state.fregs;
+        self.stack = guarded_stack(state.stack_capacity);
+        self.stack[1..1 + state.stack.len()].copy_from_slice(&state.stack);
+        self.shadow_stack = state.shadow_stack.clone();
+        self.memory = Arc::new(state.memory.clone());
+        self.is_halted = false;
+        Ok(())
+    }
+
+    pub fn reg(&self, reg: VMReg) -> usize {
+        self.get_reg(&reg)
+    }
+
+    // Same masking as a register write from bytecode
+    pub fn set_register(&mut self, reg: VMReg, val: usize) {
+        self.set_reg(&reg, val);
+    }
+
+    // Live slots only, bottom of the stack first
+    pub fn stack(&self) -> &[usize] {
+        &self.stack[1..1 + self.sp.min(self.stack.len() - 2)]
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn trace(&mut self) -> Trace<'_> {
+        Trace { vm: self, done: false }
+    }
+
+    // Memory is copy-on-write, so forking is cheap until one side writes to it
+    pub fn fork(&self) -> VM {
+        self.clone()
+    }
+
+    pub fn run(&mut self) -> Result<ExitStatus, VMError> {
+        self.fuel = self.step_limit;
+        self.execute()
+    }
+
+    // Instruction budget applied to every run, None lets it run forever
+    pub fn set_step_limit(&mut self, max_steps: Option<usize>) {
+        self.step_limit = max_steps;
+    }
+
+    // Like run, but gives up with OutOfFuel after max_steps instructions
+    pub fn run_with_limit(&mut self, max_steps: usize) -> Result<ExitStatus, VMError> {
+        self.fuel = Some(max_steps);
+        self.execute()
+    }
+
+    fn execute(&mut self) -> Result<ExitStatus, VMError> {
+        self.is_halted = false;
+        self.exit_code = 0;
+        #[cfg(feature = "std")]
+        {
+            self.start_time = std::time::Instant::now();
+        }
+        self.steps = 0;
+        self.notify(|observer| observer.on_start());
+        loop {
+            match self.step()? {
+                StepOutcome::Continue => {},
+                StepOutcome::Halted(status) => return Ok(status),
+                StepOutcome::NeedsInput => return Err(VMError::EndOfInput),
+            }
+        }
+    }
+
+    // Executes a single instruction, once the program has halted further steps do nothing
+    pub fn step(&mut self) -> Result<StepOutcome, VMError> {
+        let was_halted = self.is_halted;
+        let outcome = self.step_once();
+        match &outcome {
+            Err(err) => self.notify(|observer| observer.on_fault(err)),
+            Ok(StepOutcome::Halted(status)) if !was_halted => self.notify(|observer| observer.on_halt(*status)),
+            Ok(_) => {},
+        }
+        outcome
+    }
+
+    fn step_once(&mut self) -> Result<StepOutcome, VMError> {
+        if self.is_halted {
+            return Ok(StepOutcome::Halted(ExitStatus(self.exit_code)));
+        }
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return Err(VMError::OutOfFuel);
+            }
+            *fuel -= 1;
+        }
+        if self.steps.is_multiple_of(DEADLINE_CHECK_INTERVAL) {
+            self.check_deadline()?;
+        }
+        self.steps = self.steps.wrapping_add(1);
+        let inst = self.fetch()?;
+        let ip = self.ip;
+        // A retry after NeedsInput is the same instruction as far as hooks are concerned
+        if !self.retrying_input {
+            if let Some(hook) = self.pre_hook.clone() {
+                (*lock(&hook))(ip, &inst, self);
+            }
+        }
+        self.consumed_input.clear();
+        let result = self.exec_inst(&inst);
+        if self.input_starved {
+            // Hand back what the instruction read so the retry sees the same bytes, it
+            // only writes registers and memory it will overwrite again
+            self.input_starved = false;
+            self.retrying_input = true;
+            self.ip = ip;
+            if let Some(input) = &mut self.scripted_input {
+                for byte in self.consumed_input.drain(..).rev() {
+                    input.push_front(byte);
+                }
+            }
+            return Ok(StepOutcome::NeedsInput);
+        }
+        self.retrying_input = false;
+        // Nonblocking reads are only reported once the instruction didn't have to retry
+        for byte in core::mem::take(&mut self.consumed_input) {
+            self.notify(|observer| observer.on_input(byte));
+        }
+        result?;
+        if let Some((interval, handler)) = self.timer {
+            self.timer_ticks += 1;
+            if self.timer_ticks >= interval && !self.is_halted {
+                self.timer_ticks = 0;
+                self.interrupt(handler)?;
+            }
+        }
+        // Halting a spawned thread only ends that thread, the main thread ends the program.
+        // Parked threads saved the ip of the instruction that parked them, so switching
+        // before the increment resumes them after it
+        if self.is_halted && self.thread_id != 0 {
+            self.finished_threads.insert(self.thread_id);
+            self.is_halted = false;
+            self.switch_thread(false);
+        }
+        self.ip = self.ip.wrapping_add(1);
+        if let Some(hook) = self.post_hook.clone() {
+            (*lock(&hook))(ip, &inst, self);
+        }
+        if self.is_halted {
+            if let Some(writer) = &self.writer {
+                lock(writer).flush().map_err(io_error)?;
+            }
+            return Ok(StepOutcome::Halted(ExitStatus(self.exit_code)));
+        }
+        Ok(StepOutcome::Continue)
+    }
+
+    fn fetch(&self) -> Result<VMOp, VMError> {
+        let base = match self.code_base {
+            Some(base) => base,
+            None => return self.code.get(self.ip).copied().ok_or(VMError::IpOutOfBounds(self.ip)),
+        };
+        let addr = self.ip
+            .checked_mul(INST_SIZE)
+            .and_then(|offset| offset.checked_add(base))
+            .ok_or(VMError::MemoryOutOfBounds(usize::MAX))?;
+        self.check_mem_range(addr, INST_SIZE, PROT_EXEC)?;
+        match decode_op(&self.memory[addr..addr + INST_SIZE]) {
+            Some((op, _)) => Ok(op),
+            None => Err(VMError::InvalidInstruction(self.ip)),
+        }
+    }
+
+    fn exec_inst(&mut self, inst: &VMOp) -> Result<(), VMError>
+    {
+        match inst {
+            VMOp::PushI(imm) => { self.push(*imm)? },
+            VMOp::PushR(reg) => { self.exec_inst(&VMOp::PushI(self.get_reg(reg)))? },
+            VMOp::Pop(reg) => { let val = self.pop()?; self.set_reg(reg, val); }
+            VMOp::Peek(reg, off) => { let index = self.stack_index(*off)?; self.set_reg(reg, self.stack[index]) },
+            VMOp::Poke(reg, off) => { let index = self.stack_index(*off)?; self.stack[index] = self.get_reg(reg) },
+            VMOp::Dup => { let index = self.stack_index(1)?; self.exec_inst(&VMOp::PushI(self.stack[index]))? },
+            VMOp::Over => { let index = self.stack_index(2)?; self.exec_inst(&VMOp::PushI(self.stack[index]))? },
+            VMOp::Rot => {
+                let index = self.stack_index(3)?;
+                self.stack_index(1)?;
+                self.stack[index..index + 3].rotate_left(1)
+            },
+            VMOp::Drop => { self.pop()?; },
+            VMOp::Pusha => {
+                for reg in &[VMReg::A, VMReg::B, VMReg::C, VMReg::D] {
+                    self.exec_inst(&VMOp::PushR(*reg))?;
+                }
+            },
+            VMOp::Popa => {
+                for reg in &[VMReg::D, VMReg::C, VMReg::B, VMReg::A] {
+                    self.exec_inst(&VMOp::Pop(*reg))?;
+                }
+            },
+            VMOp::Enter(n_locals) => {
+                self.exec_inst(&VMOp::PushR(VMReg::BP))?;
+                self.bp = self.sp;
+                for _ in 0..*n_locals {
+                    self.push(0)?;
+                }
+            },
+            VMOp::Leave => { self.sp = self.bp; self.exec_inst(&VMOp::Pop(VMReg::BP))? },
+            VMOp::LdLocal(reg, slot) => { let index = self.local_index(*slot)?; self.set_reg(reg, self.stack[index]) },
+            VMOp::StLocal(reg, slot) => { let index = self.local_index(*slot)?; self.stack[index] = self.get_reg(reg) },
+            VMOp::VAdd(n) => { self.vector_op(*n, usize::checked_add, usize::wrapping_add)? },
+            VMOp::VXor(n) => { self.vector_op(*n, |left, right| Some(left ^ right), |left, right| left ^ right)? },
+            VMOp::VShl(n) => {
+                let mask = bit_mask(self.word_bits as usize);
+                let op = |left, right| shift(left, right, usize::checked_shl) & mask;
+                self.vector_op(*n, |left, right| Some(op(left, right)), op)?
+            },
+            VMOp::Mov(left, right) => { self.set_reg(left, self.get_reg(right)) },
+            VMOp::MovI(reg, imm) => { self.set_reg(reg, *imm) },
+            VMOp::Xchg(left, right) => {
+                let tmp = self.get_reg(left);
+                self.set_reg(left, self.get_reg(right));
+                self.set_reg(right, tmp);
+            },
+            VMOp::Cmov(left, right) => { if self.flags & FLAG_ZERO != 0 { self.set_reg(left, self.get_reg(right)) } },
+            VMOp::Select(dst, left, right) => {
+                let val = if self.flags & FLAG_ZERO != 0 { self.get_reg(left) } else { self.get_reg(right) };
+                self.set_reg(dst, val)
+            },
+            VMOp::Load(reg, addr) => {
+                let addr = self.get_reg(addr);
+                let val = match self.io_regions.iter_mut().find(|region| region.contains(addr)) {
+                    Some(region) => (*lock(&region.read))(addr - region.start),
+                    None => self.read_mem(addr)?,
+                };
+                self.set_reg(reg, val as usize)
+            },
+            VMOp::Store(addr, reg) => {
+                let (addr, val) = (self.get_reg(addr), self.get_reg(reg) as u8);
+                match self.io_regions.iter_mut().find(|region| region.contains(addr)) {
+                    Some(region) => (*lock(&region.write))(addr - region.start, val),
+                    None => self.write_mem(addr, val)?,
+                }
+            },
+            VMOp::LoadW(reg, addr) => {
+                let width = (self.word_bits / 8) as usize;
+                let bytes = self.mem_slice(self.get_reg(addr), width)?;
+                let val = match self.endianness {
+                    Endianness::Big => bytes.iter().fold(0, |val, byte| (val << 8) | *byte as usize),
+                    Endianness::Little => bytes.iter().rev().fold(0, |val, byte| (val << 8) | *byte as usize),
+                };
+                self.set_reg(reg, val)
+            },
+            VMOp::StoreW(addr, reg) => {
+                let (width, val, endianness) = ((self.word_bits / 8) as usize, self.get_reg(reg), self.endianness);
+                let bytes = self.mem_slice_mut(self.get_reg(addr), width)?;
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    let shift = match endianness {
+                        Endianness::Big => 8 * (width - 1 - i),
+                        Endianness::Little => 8 * i,
+                    };
+                    *byte = (val >> shift) as u8;
+                }
+            },
+            VMOp::Memcmp(left, right, len) => {
+                let (left, right) = (self.get_reg(left), self.get_reg(right));
+                let mut ordering = core::cmp::Ordering::Equal;
+                for i in 0..self.get_reg(len) {
+                    ordering = self.read_mem(byte_addr(left, i)?)?.cmp(&self.read_mem(byte_addr(right, i)?)?);
+                    if ordering != core::cmp::Ordering::Equal {
+                        break;
+                    }
+                }
+                self.flags = match ordering {
+                    core::cmp::Ordering::Equal => FLAG_ZERO,
+                    core::cmp::Ordering::Less => FLAG_CARRY,
+                    core::cmp::Ordering::Greater => 0,
+                };
+            },
+            VMOp::Memcpy(dst, src, len) => {
+                let (dst, src, len) = (self.get_reg(dst), self.get_reg(src), self.get_reg(len));
+                self.check_mem_range(src, len, PROT_READ)?;
+                self.check_mem_range(dst, len, PROT_WRITE)?;
+                Arc::make_mut(&mut self.memory).copy_within(src..src + len, dst);
+            },
+            VMOp::Memset(dst, val, len) => {
+                let (dst, val, len) = (self.get_reg(dst), self.get_reg(val), self.get_reg(len));
+                self.mem_slice_mut(dst, len)?.fill(val as u8);
+            },
+            VMOp::Alloc(reg) => {
+                let addr = match self.heap.alloc(self.get_reg(reg)) {
+                    Some(addr) => addr,
+                    None => return Err(VMError::OutOfMemory),
+                };
+                self.set_reg(reg, addr)
+            },
+            VMOp::Free(reg) => {
+                let addr = self.get_reg(reg);
+                if !self.heap.free(addr) {
+                    return Err(VMError::InvalidFree(addr));
+                }
+            },
+            VMOp::Crc32(dst, addr, len) => {
+                let (addr, len) = (self.get_reg(addr), self.get_reg(len));
+                let digest = crc32(self.mem_slice(addr, len)?);
+                self.set_reg(dst, digest as usize);
+            },
+            VMOp::Sha256(addr, len, out) => {
+                let (addr, len, out) = (self.get_reg(addr), self.get_reg(len), self.get_reg(out));
+                let digest = sha256(self.mem_slice(addr, len)?);
+                self.mem_slice_mut(out, 32)?.copy_from_slice(&digest);
+            },
+            // One Feistel round on 32-bit halves, the key register holds the precomputed sum + k[i]
+            VMOp::XteaRound(left, right, key) => {
+                let (v0, v1, key) = (self.get_reg(left) as u32, self.get_reg(right) as u32, self.get_reg(key) as u32);
+                let mixed = ((v1 << 4) ^ (v1 >> 5)).wrapping_add(v1) ^ key;
+                self.set_reg(left, v0.wrapping_add(mixed) as usize);
+            },
+            VMOp::Rc4Init(addr, len) => {
+                let (addr, len) = (self.get_reg(addr), self.get_reg(len));
+                if len == 0 {
+                    return Err(VMError::InvalidInstruction(self.ip));
+                }
+                self.rc4 = Some(Rc4::new(self.mem_slice(addr, len)?));
+            },
+            VMOp::Rc4Byte(reg) => {
+                let byte = match &mut self.rc4 {
+                    Some(rc4) => rc4.next_byte(),
+                    None => return Err(VMError::InvalidInstruction(self.ip)),
+                };
+                self.set_reg(reg, byte as usize);
+            },
+            VMOp::ModExp(base, exp, modulus) => {
+                let modulus_val = self.divisor(modulus)? as u128;
+                let mut base_val = self.get_reg(base) as u128 % modulus_val;
+                let mut exp_val = self.get_reg(exp);
+                let mut result = 1 % modulus_val;
+                while exp_val > 0 {
+                    if exp_val & 1 != 0 {
+                        result = result * base_val % modulus_val;
+                    }
+                    base_val = base_val * base_val % modulus_val;
+                    exp_val >>= 1;
+                }
+                self.set_reg(base, result as usize);
+            },
+            VMOp::FAdd(left, right) => { self.set_freg(left, self.get_freg(left) + self.get_freg(right)) },
+            VMOp::FSub(left, right) => { self.set_freg(left, self.get_freg(left) - self.get_freg(right)) },
+            VMOp::FMul(left, right) => { self.set_freg(left, self.get_freg(left) * self.get_freg(right)) },
+            VMOp::FDiv(left, right) => { self.set_freg(left, self.get_freg(left) / self.get_freg(right)) },
+            VMOp::FCmp(left, right) => {
+                // Unordered comparisons set both flags, like ucomisd
+                self.flags = match self.get_freg(left).partial_cmp(&self.get_freg(right)) {
+                    Some(core::cmp::Ordering::Equal) => FLAG_ZERO,
+                    Some(core::cmp::Ordering::Less) => FLAG_CARRY,
+                    Some(core::cmp::Ordering::Greater) => 0,
+                    None => FLAG_ZERO | FLAG_CARRY,
+                };
+            },
+            VMOp::ItoF(left, right) => { self.set_freg(left, self.get_signed(right) as f64) },
+            VMOp::FtoI(left, right) => { self.set_reg(left, self.get_freg(right) as isize as usize) },
+            VMOp::Add(left, right) => { let val = self.arith(self.get_reg(left), self.get_reg(right), usize::checked_add, usize::wrapping_add)?; self.set_reg(left, val) },
+            VMOp::AddS(left, right) => {
+                let mask = bit_mask(self.word_bits as usize);
+                self.set_reg(left, self.get_reg(left).saturating_add(self.get_reg(right)).min(mask))
+            },
+            VMOp::Sub(left, right) => { let val = self.arith(self.get_reg(left), self.get_reg(right), usize::checked_sub, usize::wrapping_sub)?; self.set_reg(left, val) },
+            VMOp::SubS(left, right) => { self.set_reg(left, self.get_reg(left).saturating_sub(self.get_reg(right))) },
+            VMOp::Inc(reg) => { let val = self.arith(self.get_reg(reg), 1, usize::checked_add, usize::wrapping_add)?; self.set_reg(reg, val) },
+            VMOp::Dec(reg) => { let val = self.arith(self.get_reg(reg), 1, usize::checked_sub, usize::wrapping_sub)?; self.set_reg(reg, val) },
+            VMOp::Mul(left, right) => { let val = self.arith(self.get_reg(left), self.get_reg(right), usize::checked_mul, usize::wrapping_mul)?; self.set_reg(left, val) },
+            VMOp::MulS(left, right) => {
+                let mask = bit_mask(self.word_bits as usize);
+                self.set_reg(left, self.get_reg(left).saturating_mul(self.get_reg(right)).min(mask))
+            },
+            VMOp::Mac(dst, left, right) => {
+                let product = self.arith(self.get_reg(left), self.get_reg(right), usize::checked_mul, usize::wrapping_mul)?;
+                let val = self.arith(self.get_reg(dst), product, usize::checked_add, usize::wrapping_add)?;
+                self.set_reg(dst, val)
+            },
+            VMOp::MacW(dst, left, right) => { self.set_reg(dst, self.get_reg(dst).wrapping_add(self.get_reg(left).wrapping_mul(self.get_reg(right)))) },
+            VMOp::MulH(left, right) => { self.set_reg(left, ((self.get_reg(left) as u128 * self.get_reg(right) as u128) >> self.word_bits) as usize) },
+            VMOp::Add128(left_hi, left_lo, right_hi, right_lo) => {
+                let val = self.get_pair(left_hi, left_lo).wrapping_add(self.get_pair(right_hi, right_lo));
+                self.set_pair(left_hi, left_lo, val);
+            },
+            VMOp::Mul128(left_hi, left_lo, right_hi, right_lo) => {
+                let val = self.get_pair(left_hi, left_lo).wrapping_mul(self.get_pair(right_hi, right_lo));
+                self.set_pair(left_hi, left_lo, val);
+            },
+            VMOp::Div(left, right) => { let val = self.get_reg(left) / self.divisor(right)?; self.set_reg(left, val) },
+            VMOp::Mod(left, right) => { let val = self.get_reg(left) % self.divisor(right)?; self.set_reg(left, val) },
+            VMOp::IDiv(left, right) => { self.divisor(right)?; self.set_reg(left, self.get_signed(left).wrapping_div(self.get_signed(right)) as usize) },
+            VMOp::IMod(left, right) => { self.divisor(right)?; self.set_reg(left, self.get_signed(left).wrapping_rem(self.get_signed(right)) as usize) },
+            VMOp::Xor(left, right) => { self.set_reg(left, self.get_reg(left) ^ self.get_reg(right)) },
+            VMOp::And(left, right) => { self.set_reg(left, self.get_reg(left) & self.get_reg(right)) },
+            VMOp::Or(left, right) => { self.set_reg(left, self.get_reg(left) | self.get_reg(right)) },
+            VMOp::Shl(left, right) => { self.set_reg(left, shift(self.get_reg(left), self.get_reg(right), usize::checked_shl)) },
+            VMOp::Shr(left, right) => { self.set_reg(left, shift(self.get_reg(left), self.get_reg(right), usize::checked_shr)) },
+            VMOp::Rol(left, right) => { self.set_reg(left, self.rotate_word(self.get_reg(left), self.get_reg(right))) },
+            VMOp::Ror(left, right) => {
+                let bits = self.word_bits as usize;
+                self.set_reg(left, self.rotate_word(self.get_reg(left), bits - self.get_reg(right) % bits))
+            },
+            VMOp::Not(reg) => { self.set_reg(reg, !self.get_reg(reg)) },
+            VMOp::Neg(reg) => { self.set_reg(reg, self.get_reg(reg).wrapping_neg()) },
+            VMOp::Popcnt(reg) => { self.set_reg(reg, self.get_reg(reg).count_ones() as usize) },
+            VMOp::Clz(reg) => {
+                // ip, sp, bp and flags aren't masked to the word, so mask before counting
+                let val = self.get_reg(reg) & bit_mask(self.word_bits as usize);
+                self.set_reg(reg, (val.leading_zeros() - (usize::BITS - self.word_bits)) as usize)
+            },
+            VMOp::Ctz(reg) => { self.set_reg(reg, self.get_reg(reg).trailing_zeros().min(self.word_bits) as usize) },
+            VMOp::Bt(left, right) => { self.test_bit(left, right); },
+            VMOp::Bts(left, right) => { let mask = self.test_bit(left, right); self.set_reg(left, self.get_reg(left) | mask) },
+            VMOp::Btc(left, right) => { let mask = self.test_bit(left, right); self.set_reg(left, self.get_reg(left) & !mask) },
+            VMOp::Bfe(dst, src, start, len) => {
+                self.check_bit_field(*start, *len)?;
+                self.set_reg(dst, (self.get_reg(src) >> start) & bit_mask(*len))
+            },
+            VMOp::Bfi(dst, src, start, len) => {
+                self.check_bit_field(*start, *len)?;
+                let mask = bit_mask(*len) << start;
+                self.set_reg(dst, (self.get_reg(dst) & !mask) | ((self.get_reg(src) << start) & mask))
+            },
+            VMOp::Inp(reg) => { 
+                let in_char = self.read_byte().ok_or(VMError::EndOfInput)? as usize;
+                if in_char == 0xd { 
+                    self.exec_inst(&VMOp::Inp(*reg))? 
+                }
+                else {
+                    self.set_reg(reg, in_char);
+                }
+            },
+            VMOp::InpLine(addr, len) => {
+                let (start, max_len) = (self.get_reg(addr), self.get_reg(len));
+                let mut count = 0;
+                while count < max_len {
+                    match self.read_byte() {
+                        Some(b'\r') => continue,
+                        Some(b'\n') | None => break,
+                        Some(byte) => {
+                            self.write_mem(byte_addr(start, count)?, byte)?;
+                            count += 1;
+                        }
+                    }
+                }
+                self.set_reg(len, count);
+            },
+            VMOp::InpInt(reg) => {
+                let mut value: usize = 0;
+                let mut byte = self.read_byte();
+                while let Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') = byte {
+                    byte = self.read_byte();
+                }
+                // Too many digits wrap around, set_reg then masks to the word width
+                while let Some(digit @ b'0'..=b'9') = byte {
+                    value = value.wrapping_mul(10).wrapping_add((digit - b'0') as usize);
+                    byte = self.read_byte();
+                }
+                self.set_reg(reg, value);
+            },
+            VMOp::InpN(reg, n) => {
+                if *n > self.word_bits as usize / 8 {
+                    return Err(VMError::InvalidInstruction(self.ip));
+                }
+                let mut value = 0;
+                for i in 0..*n {
+                    self.exec_inst(&VMOp::Inp(*reg))?;
+                    value = match self.endianness {
+                        Endianness::Big => (value << 8) | self.get_reg(reg),
+                        Endianness::Little => value | (self.get_reg(reg) << (8 * i)),
+                    };
+                }
+                self.set_reg(reg, value);
+            },
+            VMOp::Eq(left, right) => { if self.get_reg(left) != self.get_reg(right) {self.ip += 1} },
+            VMOp::Gt(left, right) => { if self.get_reg(left) <= self.get_reg(right) {self.ip += 1} },
+            VMOp::Lt(left, right) => { if self.get_reg(left) >= self.get_reg(right) {self.ip += 1} },
+            VMOp::SGt(left, right) => { if self.get_signed(left) <= self.get_signed(right) {self.ip += 1} },
+            VMOp::SLt(left, right) => { if self.get_signed(left) >= self.get_signed(right) {self.ip += 1} },
+            VMOp::Cmp(left, right) => {
+                let (left, right) = (self.get_reg(left), self.get_reg(right));
+                self.flags = 0;
+                if left == right { self.flags |= FLAG_ZERO }
+                if left < right { self.flags |= FLAG_CARRY }
+            },
+            VMOp::Jmp(addr) => { self.ip = addr.wrapping_sub(1) },
+            VMOp::JmpRel(off) => {
+                let addr = self.ip.checked_add_signed(*off).ok_or(VMError::IpOutOfBounds(usize::MAX))?;
+                self.exec_inst(&VMOp::Jmp(addr))?
+            },
+            VMOp::JmpR(reg) => {
+                let addr = self.get_reg(reg);
+                if addr >= self.code.len() {
+                    return Err(VMError::IpOutOfBounds(addr));
+                }
+                self.exec_inst(&VMOp::Jmp(addr))?
+            },
+            VMOp::Je(addr) => { if self.flags & FLAG_ZERO != 0 { self.exec_inst(&VMOp::Jmp(*addr))? } },
+            VMOp::Jne(addr) => { if self.flags & FLAG_ZERO == 0 { self.exec_inst(&VMOp::Jmp(*addr))? } },
+            VMOp::Jg(addr) => { if self.flags & (FLAG_ZERO | FLAG_CARRY) == 0 { self.exec_inst(&VMOp::Jmp(*addr))? } },
+            VMOp::Jge(addr) => { if self.flags & FLAG_CARRY == 0 { self.exec_inst(&VMOp::Jmp(*addr))? } },
+            VMOp::Jl(addr) => { if self.flags & FLAG_CARRY != 0 { self.exec_inst(&VMOp::Jmp(*addr))? } },
+            VMOp::Jle(addr) => { if self.flags & (FLAG_ZERO | FLAG_CARRY) != 0 { self.exec_inst(&VMOp::Jmp(*addr))? } },
+            VMOp::Call(addr) => {
+                self.push_return(self.ip + 1)?;
+                self.notify(|observer| observer.on_call(self.ip, *addr));
+                self.exec_inst(&VMOp::Jmp(*addr))?;
+            },
+            VMOp::CallR(reg) => {
+                self.push_return(self.ip + 1)?;
+                self.notify(|observer| observer.on_call(self.ip, self.get_reg(reg)));
+                self.exec_inst(&VMOp::JmpR(*reg))?;
+            },
+            VMOp::Ret => {
+                let addr = self.pop_return()?;
+                self.notify(|observer| observer.on_return(self.ip, addr.wrapping_add(1)));
+                self.ip = addr;
+            }
+            VMOp::Int(num) => {
+                let addr = match self.interrupt_vectors.get(num) {
+                    Some(addr) => *addr,
+                    None => return Err(VMError::UnknownInterrupt(*num)),
+                };
+                self.interrupt(addr)?;
+            },
+            VMOp::Iret => { self.exec_inst(&VMOp::Pop(VMReg::Flags))?; self.exec_inst(&VMOp::Pop(VMReg::IP))?; },
+            VMOp::Print(reg) => { self.write_out(format_args!("{}", self.get_reg(reg) as u8 as char))? },
+            VMOp::Rand(reg) => { let val = self.next_rand(); self.set_reg(reg, val as usize) },
+            VMOp::Time(reg) => {
+                let millis = match &self.time_source {
+                    Some(source) => (*lock(source))(),
+                    None if self.deterministic => self.virtual_time,
+                    None => self.elapsed().as_millis() as u64,
+                };
+                self.set_reg(reg, millis as usize)
+            },
+            VMOp::Sleep(reg) if self.deterministic => {
+                self.virtual_time = self.virtual_time.wrapping_add(self.get_reg(reg) as u64);
+            },
+            VMOp::Sleep(reg) => {
+                // Saturates rather than overflowing when a huge duration is scaled up
+                let secs = self.get_reg(reg) as f64 / 1000.0 * self.sleep_scale;
+                let mut duration = Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX);
+                // Never sleep past the deadline, the check afterwards reports it
+                if let Some(deadline) = self.deadline {
+                    duration = duration.min(deadline.saturating_sub(self.elapsed()));
+                }
+                self.host_sleep(duration);
+                self.check_deadline()?;
+            },
+            VMOp::Syscall(num) => {
+                let handler = match self.syscalls.get(num) {
+                    Some(handler) => handler.clone(),
+                    None => return Err(VMError::UnknownSyscall(*num)),
+                };
+                (*lock(&handler))(self);
+            },
+            VMOp::Ext(op) => {
+                let handler = match &self.ext_handler {
+                    Some(handler) => handler.clone(),
+                    None => return Err(VMError::InvalidInstruction(self.ip)),
+                };
+                lock(&handler).exec(*op, self)?;
+            },
+            VMOp::Spawn(reg, addr) => {
+                let id = self.next_thread_id;
+                self.next_thread_id += 1;
+                self.threads.push_back(ThreadContext {
+                    id,
+                    ip: addr.wrapping_sub(1),
+                    stack: guarded_stack(0),
+                    shadow_stack: self.shadow_stack.as_ref().map(|_| Vec::new()),
+                    ..ThreadContext::default()
+                });
+                self.set_reg(reg, id);
+            },
+            VMOp::Yield => { self.switch_thread(true) },
+            VMOp::Join(reg) => {
+                let id = self.get_reg(reg);
+                if id == self.thread_id || id >= self.next_thread_id {
+                    return Err(VMError::InvalidThread(id));
+                }
+                if !self.finished_threads.contains(&id) {
+                    // Retry the Join once the other threads have had a turn
+                    self.ip = self.ip.wrapping_sub(1);
+                    self.switch_thread(true);
+                }
+            },
+            // Like cmpxchg: stores new and sets the zero flag if the byte matched expected,
+            // otherwise loads the current byte into expected
+            VMOp::Cas(addr, expected, new) => {
+                let addr_val = self.get_reg(addr);
+                let current = self.read_mem(addr_val)? as usize;
+                if current == self.get_reg(expected) & 0xff {
+                    self.write_mem(addr_val, self.get_reg(new) as u8)?;
+                    self.flags |= FLAG_ZERO;
+                }
+                else {
+                    self.set_reg(expected, current);
+                    self.flags &= !FLAG_ZERO;
+                }
+            },
+            VMOp::Getenv(..) | VMOp::FRead(..) if self.deterministic => {
+                return Err(VMError::Nondeterministic(self.ip));
+            },
+            #[cfg(not(feature = "std"))]
+            VMOp::Getenv(..) | VMOp::FRead(..) => return Err(VMError::AccessDenied(self.ip)),
+            #[cfg(feature = "std")]
+            VMOp::Getenv(name, out, len) => {
+                if !self.allow_getenv {
+                    return Err(VMError::AccessDenied(self.ip));
+                }
+                let name_bytes = self.read_cstr(self.get_reg(name))?;
+                let value = String::from_utf8(name_bytes)
+                    .ok()
+                    .and_then(|name| std::env::var(name).ok())
+                    .unwrap_or_default();
+                let (out_addr, count) = (self.get_reg(out), value.len().min(self.get_reg(len)));
+                self.mem_slice_mut(out_addr, count)?.copy_from_slice(&value.as_bytes()[..count]);
+                self.set_reg(len, count);
+            },
+            #[cfg(feature = "std")]
+            VMOp::FRead(path, out, len) => {
+                let path_bytes = self.read_cstr(self.get_reg(path))?;
+                let path = std::path::PathBuf::from(String::from_utf8_lossy(&path_bytes).into_owned());
+                if !self.readable_files.contains(&path) {
+                    return Err(VMError::AccessDenied(self.ip));
+                }
+                let data = match std::fs::read(&path) {
+                    Ok(data) => data,
+                    Err(err) => return Err(io_error(err)),
+                };
+                let (out_addr, count) = (self.get_reg(out), data.len().min(self.get_reg(len)));
+                self.mem_slice_mut(out_addr, count)?.copy_from_slice(&data[..count]);
+                self.set_reg(len, count);
+            },
+            VMOp::PrintInt(reg) => { self.write_out(format_args!("{}", self.get_reg(reg)))? },
+            VMOp::PrintHex(reg) => { self.write_out(format_args!("{:#x}", self.get_reg(reg)))? },
+            VMOp::PrintStr(reg) => {
+                for byte in self.read_cstr(self.get_reg(reg))? {
+                    self.write_out(format_args!("{}", byte as char))?;
+                }
+            },
+            VMOp::Brk => {
+                if let Some(handler) = &self.breakpoint_handler {
+                    (*lock(handler))(&self.snapshot());
+                }
+            },
+            VMOp::Nop => {},
+            VMOp::Halt => { self.exit_code = 0; self.is_halted = true; },
+            VMOp::HaltCode(reg) => { self.exit_code = self.get_reg(reg); self.is_halted = true; },
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, val: usize) -> Result<(), VMError> {
+        let len = self.stack.len() - 2;
+        if self.sp >= len {
+            if self.sp >= self.max_stack_size {
+                return Err(VMError::StackOverflow);
+            }
+            let new_len = (len * 2).max(self.sp + 1).min(self.max_stack_size);
+            let top = self.stack.pop().unwrap();
+            self.stack.resize(new_len + 1, 0);
+            self.stack.push(top);
+        }
+        self.stack[self.sp + 1] = val;
+        self.sp += 1;
+        self.check_stack()
+    }
+
+    // Wall-clock time since run started, or the virtual clock without std
+    #[cfg(feature = "std")]
+    fn elapsed(&self) -> Duration {
+        self.start_time.elapsed()
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn elapsed(&self) -> Duration {
+        Duration::from_millis(self.virtual_time)
+    }
+
+    #[cfg(feature = "std")]
+    fn host_sleep(&mut self, duration: Duration) {
+        if !duration.is_zero() {
+            std::thread::sleep(duration);
+        }
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn host_sleep(&mut self, duration: Duration) {
+        self.virtual_time = self.virtual_time.wrapping_add(duration.as_millis() as u64);
+    }
+
+    fn check_deadline(&self) -> Result<(), VMError> {
+        match self.deadline {
+            Some(deadline) if self.elapsed() >= deadline => Err(VMError::DeadlineExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_stack(&self) -> Result<(), VMError> {
+        if !self.check_stack_guards {
+            return Ok(());
+        }
+        let top = self.stack.len() - 1;
+        if self.stack[0] != STACK_CANARY {
+            return Err(VMError::StackCorruption(0));
+        }
+        if self.stack[top] != STACK_CANARY {
+            return Err(VMError::StackCorruption(top));
+        }
+        Ok(())
+    }
+
+    // Round-robin: parks the current thread at the back of the queue (unless it exited)
+    // and resumes the one at the front
+    fn switch_thread(&mut self, save_current: bool) {
+        let next = match self.threads.pop_front() {
+            Some(next) => next,
+            None => return,
+        };
+        if save_current {
+            let current = ThreadContext {
+                id: self.thread_id,
+                regs: self.regs,
+                ip: self.ip,
+                sp: self.sp,
+                bp: self.bp,
+                flags: self.flags,
+                fregs: self.fregs,
+                stack: core::mem::take(&mut self.stack),
+                shadow_stack: self.shadow_stack.take()
+            };
+            self.threads.push_back(current);
+        }
+        self.thread_id = next.id;
+        self.regs = next.regs;
+        self.ip = next.ip;
+        self.sp = next.sp;
+        self.bp = next.bp;
+        self.flags = next.flags;
+        self.fregs = next.fregs;
+        self.stack = next.stack;
+        self.shadow_stack = next.shadow_stack;
+    }
+
+    fn push_return(&mut self, addr: usize) -> Result<(), VMError> {
+        match &mut self.shadow_stack {
+            Some(shadow_stack) if shadow_stack.len() >= self.max_stack_size => Err(VMError::StackOverflow),
+            Some(shadow_stack) => { shadow_stack.push(addr); Ok(()) },
+            None => self.push(addr),
+        }
+    }
+
+    fn pop_return(&mut self) -> Result<usize, VMError> {
+        match &mut self.shadow_stack {
+            Some(shadow_stack) => shadow_stack.pop().ok_or(VMError::StackUnderflow),
+            None => self.pop(),
+        }
+    }
+
+    fn pop(&mut self) -> Result<usize, VMError> {
+        let index = self.stack_index(1)?;
+        self.sp -= 1;
+        self.check_stack()?;
+        Ok(self.stack[index])
+    }
+
+    // Index into the stack allocation of the slot depth entries below sp, so depth 1 is the
+    // top of the stack
+    fn stack_index(&self, depth: usize) -> Result<usize, VMError> {
+        if depth > self.sp {
+            return Err(VMError::StackUnderflow);
+        }
+        if self.sp - depth >= self.stack.len() - 2 {
+            return Err(VMError::StackOverflow);
+        }
+        Ok(self.sp - depth + 1)
+    }
+
+    // Index of a local in the current frame, which must lie below sp
+    fn local_index(&self, slot: usize) -> Result<usize, VMError> {
+        let index = self.bp.checked_add(slot).ok_or(VMError::StackOverflow)?;
+        if index >= self.sp {
+            return Err(VMError::StackOverflow);
+        }
+        self.stack_index(self.sp - index)
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        if let Some(input) = &mut self.scripted_input {
+            let byte = match input.pop_front() {
+                Some(byte) => byte,
+                None => {
+                    self.input_starved = self.nonblocking_input;
+                    return None;
+                },
+            };
+            if self.nonblocking_input {
+                self.consumed_input.push(byte);
+            }
+            else {
+                self.notify(|observer| observer.on_input(byte));
+            }
+            return Some(byte);
+        }
+        if self.deterministic {
+            return None;
+        }
+        let mut buf = [0u8; 1];
+        lock(self.reader.as_ref()?).read_exact(&mut buf).ok()?;
+        self.notify(|observer| observer.on_input(buf[0]));
+        Some(buf[0])
+    }
+
+    fn write_out(&mut self, args: core::fmt::Arguments) -> Result<(), VMError> {
+        let text = args.to_string();
+        self.notify(|observer| observer.on_output(text.as_bytes()));
+        if let Some(output) = &mut self.captured_output {
+            output.extend_from_slice(text.as_bytes());
+            return Ok(());
+        }
+        match &self.writer {
+            Some(writer) => lock(writer).write_all(text.as_bytes()).map_err(io_error),
+            None => Ok(()),
+        }
+    }
+
+    fn notify<F: FnMut(&mut dyn Observer)>(&self, mut event: F) {
+        for observer in &self.observers {
+            event(&mut *lock(observer));
+        }
+    }
+
+    // splitmix64
+    fn next_rand(&mut self) -> u64 {
+        self.rng_state = self.rng_state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Combines the top n slots element-wise into the n slots below them, then pops the top n
+    // Lanes overflow like arith, but a trap is only raised once the whole vector is written
+    fn vector_op<C, W>(&mut self, n: usize, checked: C, wrapping: W) -> Result<(), VMError>
+    where
+        C: Fn(usize, usize) -> Option<usize>,
+        W: Fn(usize, usize) -> usize
+    {
+        if n == 0 {
+            return Ok(());
+        }
+        let base = self.stack_index(n.checked_mul(2).ok_or(VMError::StackUnderflow)?)?;
+        self.stack_index(1)?;
+        let mask = bit_mask(self.word_bits as usize);
+        let lane = |left, right| checked(left, right).filter(|val| *val <= mask);
+        let overflowed = (0..n).any(|i| lane(self.stack[base + i], self.stack[base + n + i]).is_none());
+        if overflowed && self.overflow_mode == OverflowMode::Checked {
+            return Err(VMError::ArithmeticOverflow);
+        }
+        for i in 0..n {
+            let (left, right) = (self.stack[base + i], self.stack[base + n + i]);
+            self.stack[base + i] = lane(left, right).unwrap_or_else(|| wrapping(left, right) & mask);
+        }
+        self.sp -= n;
+        if overflowed {
+            self.overflow()?;
+        }
+        Ok(())
+    }
+
+    // Bit fields have to fit inside a word
+    fn check_bit_field(&self, start: usize, len: usize) -> Result<(), VMError> {
+        match start.checked_add(len) {
+            Some(end) if start < self.word_bits as usize && end <= self.word_bits as usize => Ok(()),
+            _ => Err(VMError::InvalidInstruction(self.ip)),
+        }
+    }
+
+    // Copies the selected bit into the carry flag and returns its mask
+    fn test_bit(&mut self, reg: &VMReg, index: &VMReg) -> usize {
+        let mask = 1 << (self.get_reg(index) % self.word_bits as usize);
+        if self.get_reg(reg) & mask != 0 {
+            self.flags |= FLAG_CARRY;
+        }
+        else {
+            self.flags &= !FLAG_CARRY;
+        }
+        mask
+    }
+
+    fn read_cstr(&self, addr: usize) -> Result<Vec<u8>, VMError> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.read_mem(byte_addr(addr, bytes.len())?)?;
+            if byte == 0 {
+                return Ok(bytes);
+            }
+            bytes.push(byte);
+        }
+    }
+
+    // Faults unless every page touched by the range grants the requested access
+    fn check_mem_range(&self, addr: usize, len: usize, access: u8) -> Result<(), VMError> {
+        let end = match addr.checked_add(len) {
+            Some(end) if end <= self.memory.len() => end,
+            _ => return Err(VMError::MemoryOutOfBounds(addr)),
+        };
+        if len == 0 {
+            return Ok(());
+        }
+        for page in addr / PAGE_SIZE..=(end - 1) / PAGE_SIZE {
+            if self.page_flags[page] & access != access {
+                return Err(VMError::ProtectionFault(addr.max(page * PAGE_SIZE)));
+            }
+        }
+        Ok(())
+    }
+
+    fn mem_slice(&self, addr: usize, len: usize) -> Result<&[u8], VMError> {
+        self.check_mem_range(addr, len, PROT_READ)?;
+        Ok(&self.memory[addr..addr + len])
+    }
+
+    fn mem_slice_mut(&mut self, addr: usize, len: usize) -> Result<&mut [u8], VMError> {
+        self.check_mem_range(addr, len, PROT_WRITE)?;
+        Ok(&mut Arc::make_mut(&mut self.memory)[addr..addr + len])
+    }
+
+    fn read_mem(&self, addr: usize) -> Result<u8, VMError> {
+        Ok(self.mem_slice(addr, 1)?[0])
+    }
+
+    fn write_mem(&mut self, addr: usize, val: u8) -> Result<(), VMError> {
+        self.mem_slice_mut(addr, 1)?[0] = val;
+        Ok(())
+    }
+
+    // Register pairs hold wide values as hi:lo
+    fn get_pair(&self, hi: &VMReg, lo: &VMReg) -> u128 {
+        ((self.get_reg(hi) as u128) << self.word_bits) | self.get_reg(lo) as u128
+    }
+
+    fn set_pair(&mut self, hi: &VMReg, lo: &VMReg, val: u128) {
+        self.set_reg(hi, (val >> self.word_bits) as usize);
+        self.set_reg(lo, val as usize);
+    }
+
+    fn get_freg(&self, reg: &VMFReg) -> f64 {
+        self.fregs[*reg as usize]
+    }
+
+    fn set_freg(&mut self, reg: &VMFReg, val: f64) {
+        self.fregs[*reg as usize] = val;
+    }
+
+    // Applies the overflow mode to an Add/Sub/Mul-style operation at the configured word width
+    fn arith(&mut self, left: usize, right: usize, checked: fn(usize, usize) -> Option<usize>, wrapping: fn(usize, usize) -> usize) -> Result<usize, VMError> {
+        let mask = bit_mask(self.word_bits as usize);
+        if let Some(val) = checked(left, right).filter(|val| *val <= mask) {
+            return Ok(val);
+        }
+        self.overflow()?;
+        Ok(wrapping(left, right) & mask)
+    }
+
+    fn overflow(&mut self) -> Result<(), VMError> {
+        match self.overflow_mode {
+            OverflowMode::Wrapping => Ok(()),
+            OverflowMode::Checked => Err(VMError::ArithmeticOverflow),
+            OverflowMode::Trap(handler) => {
+                self.flags |= FLAG_OVERFLOW;
+                match handler {
+                    Some(addr) => self.interrupt(addr),
+                    None => Ok(()),
+                }
+            },
+        }
+    }
+
+    // Saves ip and flags for Iret and transfers control to a handler
+    fn interrupt(&mut self, addr: usize) -> Result<(), VMError> {
+        self.exec_inst(&VMOp::PushI(self.ip))?;
+        self.exec_inst(&VMOp::PushR(VMReg::Flags))?;
+        self.exec_inst(&VMOp::Jmp(addr))
+    }
+
+    // Faults on a zero divisor instead of letting the host division panic
+    fn divisor(&self, reg: &VMReg) -> Result<usize, VMError> {
+        match self.get_reg(reg) {
+            0 => Err(VMError::DivisionByZero),
+            val => Ok(val),
+        }
+    }
+
+    // Sign-extends a register from the configured word width
+    fn get_signed(&self, reg: &VMReg) -> isize {
+        let shift = usize::BITS - self.word_bits;
+        ((self.get_reg(reg) << shift) as isize) >> shift
+    }
+
+    fn rotate_word(&self, val: usize, n: usize) -> usize {
+        let bits = self.word_bits as usize;
+        match n % bits {
+            0 => val,
+            n => ((val << n) | (val >> (bits - n))) & bit_mask(bits),
+        }
+    }
+
+    fn get_reg(&self, reg: &VMReg) -> usize {
+        if let Some(i) = reg.gpr_index() {
+            return self.regs[i];
+        }
+        match reg {
+            VMReg::IP => self.ip,
+            VMReg::SP => self.sp,
+            VMReg::BP => self.bp,
+            VMReg::Flags => self.flags,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_reg(&mut self, reg: &VMReg, val: usize) {
+        if let Some(i) = reg.gpr_index() {
+            self.regs[i] = val & bit_mask(self.word_bits as usize);
+            return;
+        }
+        match reg {
+            VMReg::IP => self.ip = val,
+            VMReg::SP => self.sp = val,
+            VMReg::BP => self.bp = val,
+            VMReg::Flags => self.flags = val,
+            _ => unreachable!(),
+        }
+    }
+}
+
+// Host callbacks aren't printable, so only the machine state is shown
+impl core::fmt::Debug for VM {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("VM")
+            .field("regs", &self.regs)
+            .field("ip", &self.ip)
+            .field("sp", &self.sp)
+            .field("bp", &self.bp)
+            .field("flags", &self.flags)
+            .field("fregs", &self.fregs)
+            .field("stack", &self.stack())
+            .field("thread_id", &self.thread_id)
+            .field("is_halted", &self.is_halted)
+            .field("exit_code", &self.exit_code)
+            .finish_non_exhaustive()
+    }
+}
+
+// Compares machine state only, host handlers and configuration are ignored. Float registers
+// compare by bit pattern so a NaN matches itself
+impl PartialEq for VM {
+    fn eq(&self, other: &VM) -> bool {
+        self.regs == other.regs
+            && self.ip == other.ip
+            && self.sp == other.sp
+            && self.bp == other.bp
+            && self.flags == other.flags
+            && self.fregs.map(f64::to_bits) == other.fregs.map(f64::to_bits)
+            && self.stack() == other.stack()
+            && self.shadow_stack == other.shadow_stack
+            && self.memory == other.memory
+            && self.is_halted == other.is_halted
+            && self.exit_code == other.exit_code
+    }
+}
+
+// Collects construction options so VM::new doesn't need a variant for every combination
+#[derive(Clone, Default)]
+pub struct VMBuilder {
+    stack_size: Option<usize>,
+    max_stack_size: Option<usize>,
+    memory_size: Option<usize>,
+    data: Vec<u8>,
+    endianness: Option<Endianness>,
+    word_bits: Option<u32>,
+    overflow_mode: Option<OverflowMode>,
+    seed: Option<u64>,
+    deterministic: Option<u64>,
+    input: Option<Vec<u8>>,
+    reader: Option<InputHandle>,
+    writer: Option<OutputHandle>,
+    capture_output: bool,
+    step_limit: Option<usize>,
+    deadline: Option<Duration>,
+    shadow_stack: bool,
+    stack_guard_checks: Option<bool>
+}
+
+impl VMBuilder {
+    pub fn new() -> VMBuilder {
+        VMBuilder::default()
+    }
+
+    pub fn stack_size(mut self, stack_size: usize) -> VMBuilder {
+        self.stack_size = Some(stack_size);
+        self
+    }
+
+    pub fn max_stack_size(mut self, max_size: usize) -> VMBuilder {
+        self.max_stack_size = Some(max_size);
+        self
+    }
+
+    pub fn memory_size(mut self, memory_size: usize) -> VMBuilder {
+        self.memory_size = Some(memory_size);
+        self
+    }
+
+    // Copied to address 0, memory defaults to twice its size when memory_size isn't given
+    pub fn data(mut self, data: Vec<u8>) -> VMBuilder {
+        self.data = data;
+        self
+    }
+
+    pub fn endianness(mut self, endianness: Endianness) -> VMBuilder {
+        self.endianness = Some(endianness);
+        self
+    }
+
+    pub fn word_size(mut self, bits: u32) -> VMBuilder {
+        self.word_bits = Some(bits);
+        self
+    }
+
+    pub fn overflow_mode(mut self, mode: OverflowMode) -> VMBuilder {
+        self.overflow_mode = Some(mode);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> VMBuilder {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn deterministic(mut self, seed: u64) -> VMBuilder {
+        self.deterministic = Some(seed);
+        self
+    }
+
+    pub fn input<I: Into<Vec<u8>>>(mut self, input: I) -> VMBuilder {
+        self.input = Some(input.into());
+        self
+    }
+
+    pub fn reader<R: Read + Send + 'static>(mut self, reader: R) -> VMBuilder {
+        self.reader = Some(Arc::new(Lock::new(reader)));
+        self
+    }
+
+    pub fn writer<W: Write + Send + 'static>(mut self, writer: W) -> VMBuilder {
+        self.writer = Some(Arc::new(Lock::new(writer)));
+        self
+    }
+
+    pub fn capture_output(mut self, enabled: bool) -> VMBuilder {
+        self.capture_output = enabled;
+        self
+    }
+
+    pub fn step_limit(mut self, max_steps: usize) -> VMBuilder {
+        self.step_limit = Some(max_steps);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Duration) -> VMBuilder {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn shadow_stack(mut self, enabled: bool) -> VMBuilder {
+        self.shadow_stack = enabled;
+        self
+    }
+
+    pub fn stack_guard_checks(mut self, enabled: bool) -> VMBuilder {
+        self.stack_guard_checks = Some(enabled);
+        self
+    }
+
+    // Fails if the data image doesn't fit in memory or the word size is unsupported
+    pub fn build(self, code: Vec<VMOp>) -> Result<VM, VMError> {
+        let memory_size = self.memory_size.unwrap_or_else(|| DEFAULT_MEMORY_SIZE.max(self.data.len() * 2));
+        if self.data.len() > memory_size {
+            return Err(VMError::MemoryOutOfBounds(memory_size));
+        }
+        let mut vm = VM::with_sizes(code, self.stack_size.unwrap_or(DEFAULT_STACK_SIZE), memory_size);
+        Arc::make_mut(&mut vm.memory)[..self.data.len()].copy_from_slice(&self.data);
+        vm.data = self.data;
+        if let Some(max_size) = self.max_stack_size {
+            vm.set_max_stack_size(max_size);
+        }
+        if let Some(endianness) = self.endianness {
+            vm.set_endianness(endianness);
+        }
+        if let Some(bits) = self.word_bits {
+            vm.set_word_size(bits)?;
+        }
+        if let Some(mode) = self.overflow_mode {
+            vm.set_overflow_mode(mode);
+        }
+        if let Some(seed) = self.seed {
+            vm.set_seed(seed);
+        }
+        if let Some(seed) = self.deterministic {
+            vm.set_deterministic(seed);
+        }
+        if let Some(input) = self.input {
+            vm.set_input(input);
+        }
+        if let Some(reader) = self.reader {
+            vm.reader = Some(reader);
+        }
+        if let Some(writer) = self.writer {
+            vm.writer = Some(writer);
+        }
+        if let Some(deadline) = self.deadline {
+            vm.set_deadline(deadline);
+        }
+        if let Some(enabled) = self.stack_guard_checks {
+            vm.set_stack_guard_checks(enabled);
+        }
+        vm.set_capture_output(self.capture_output);
+        vm.set_step_limit(self.step_limit);
+        vm.set_shadow_stack(self.shadow_stack);
+        Ok(vm)
+    }
+}
+
+// Instructions are an opcode byte followed by their operands, registers take one byte and
+// immediates eight little-endian bytes
+trait Operand: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+    fn decode(bytes: &[u8]) -> Option<(Self, usize)>;
+}
+
+impl Operand for VMReg {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(VMReg, usize)> {
+        let reg = match bytes.first()? {
+            0 => VMReg::A,
+            1 => VMReg::B,
+            2 => VMReg::C,
+            3 => VMReg::D,
+            4 => VMReg::IP,
+            5 => VMReg::SP,
+            6 => VMReg::BP,
+            7 => VMReg::Flags,
+            8 => VMReg::R4,
+            9 => VMReg::R5,
+            10 => VMReg::R6,
+            11 => VMReg::R7,
+            12 => VMReg::R8,
+            13 => VMReg::R9,
+            14 => VMReg::R10,
+            15 => VMReg::R11,
+            16 => VMReg::R12,
+            17 => VMReg::R13,
+            18 => VMReg::R14,
+            19 => VMReg::R15,
+            _ => return None,
+        };
+        Some((reg, 1))
+    }
+}
+
+impl Operand for VMFReg {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(VMFReg, usize)> {
+        let reg = match bytes.first()? {
+            0 => VMFReg::F0,
+            1 => VMFReg::F1,
+            2 => VMFReg::F2,
+            3 => VMFReg::F3,
+            _ => return None,
+        };
+        Some((reg, 1))
+    }
+}
+
+impl Operand for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(*self as u64).to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(usize, usize)> {
+        let imm = u64::from_le_bytes(bytes.get(..8)?.try_into().ok()?);
+        Some((imm as usize, 8))
+    }
+}
+
+impl Operand for u16 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(u16, usize)> {
+        let imm = u16::from_le_bytes(bytes.get(..2)?.try_into().ok()?);
+        Some((imm, 2))
+    }
+}
+
+impl Operand for isize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(*self as i64).to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<(isize, usize)> {
+        let imm = i64::from_le_bytes(bytes.get(..8)?.try_into().ok()?);
+        Some((imm as isize, 8))
+    }
+}
+
+struct OperandReader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl OperandReader<'_> {
+    fn read<T: Operand>(&mut self) -> Option<T> {
+        let (val, len) = T::decode(&self.bytes[self.pos..])?;
+        self.pos += len;
+        Some(val)
+    }
+}
+
+fn encode_op(op: &VMOp, out: &mut Vec<u8>) {
+    match op {
+        VMOp::PushI(a) => { out.push(0x00); a.encode(out); },
+        VMOp::PushR(a) => { out.push(0x01); a.encode(out); },
+        VMOp::Pop(a) => { out.push(0x02); a.encode(out); },
+        VMOp::Peek(a, b) => { out.push(0x03); a.encode(out); b.encode(out); },
+        VMOp::Poke(a, b) => { out.push(0x04); a.encode(out); b.encode(out); },
+        VMOp::Dup => out.push(0x05),
+        VMOp::Over => out.push(0x06),
+        VMOp::Rot => out.push(0x07),
+        VMOp::Drop => out.push(0x08),
+        VMOp::Pusha => out.push(0x09),
+        VMOp::Popa => out.push(0x0a),
+        VMOp::Enter(a) => { out.push(0x0b); a.encode(out); },
+        VMOp::Leave => out.push(0x0c),
+        VMOp::LdLocal(a, b) => { out.push(0x0d); a.encode(out); b.encode(out); },
+        VMOp::StLocal(a, b) => { out.push(0x0e); a.encode(out); b.encode(out); },
+        VMOp::VAdd(a) => { out.push(0x0f); a.encode(out); },
+        VMOp::VXor(a) => { out.push(0x10); a.encode(out); },
+        VMOp::VShl(a) => { out.push(0x11); a.encode(out); },
+        VMOp::Mov(a, b) => { out.push(0x12); a.encode(out); b.encode(out); },
+        VMOp::MovI(a, b) => { out.push(0x13); a.encode(out); b.encode(out); },
+        VMOp::Xchg(a, b) => { out.push(0x14); a.encode(out); b.encode(out); },
+        VMOp::Cmov(a, b) => { out.push(0x15); a.encode(out); b.encode(out); },
+        VMOp::Select(a, b, c) => { out.push(0x16); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::Load(a, b) => { out.push(0x17); a.encode(out); b.encode(out); },
+        VMOp::Store(a, b) => { out.push(0x18); a.encode(out); b.encode(out); },
+        VMOp::Memcmp(a, b, c) => { out.push(0x19); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::Memcpy(a, b, c) => { out.push(0x1a); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::Memset(a, b, c) => { out.push(0x1b); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::Alloc(a) => { out.push(0x1c); a.encode(out); },
+        VMOp::Free(a) => { out.push(0x1d); a.encode(out); },
+        VMOp::Crc32(a, b, c) => { out.push(0x1e); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::Sha256(a, b, c) => { out.push(0x1f); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::XteaRound(a, b, c) => { out.push(0x20); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::Rc4Init(a, b) => { out.push(0x21); a.encode(out); b.encode(out); },
+        VMOp::Rc4Byte(a) => { out.push(0x22); a.encode(out); },
+        VMOp::ModExp(a, b, c) => { out.push(0x23); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::FAdd(a, b) => { out.push(0x24); a.encode(out); b.encode(out); },
+        VMOp::FSub(a, b) => { out.push(0x25); a.encode(out); b.encode(out); },
+        VMOp::FMul(a, b) => { out.push(0x26); a.encode(out); b.encode(out); },
+        VMOp::FDiv(a, b) => { out.push(0x27); a.encode(out); b.encode(out); },
+        VMOp::FCmp(a, b) => { out.push(0x28); a.encode(out); b.encode(out); },
+        VMOp::ItoF(a, b) => { out.push(0x29); a.encode(out); b.encode(out); },
+        VMOp::FtoI(a, b) => { out.push(0x2a); a.encode(out); b.encode(out); },
+        VMOp::Add(a, b) => { out.push(0x2b); a.encode(out); b.encode(out); },
+        VMOp::AddS(a, b) => { out.push(0x2c); a.encode(out); b.encode(out); },
+        VMOp::Sub(a, b) => { out.push(0x2d); a.encode(out); b.encode(out); },
+        VMOp::SubS(a, b) => { out.push(0x2e); a.encode(out); b.encode(out); },
+        VMOp::Inc(a) => { out.push(0x2f); a.encode(out); },
+        VMOp::Dec(a) => { out.push(0x30); a.encode(out); },
+        VMOp::Mul(a, b) => { out.push(0x31); a.encode(out); b.encode(out); },
+        VMOp::MulS(a, b) => { out.push(0x32); a.encode(out); b.encode(out); },
+        VMOp::MulH(a, b) => { out.push(0x33); a.encode(out); b.encode(out); },
+        VMOp::Mac(a, b, c) => { out.push(0x34); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::MacW(a, b, c) => { out.push(0x35); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::Add128(a, b, c, d) => { out.push(0x36); a.encode(out); b.encode(out); c.encode(out); d.encode(out); },
+        VMOp::Mul128(a, b, c, d) => { out.push(0x37); a.encode(out); b.encode(out); c.encode(out); d.encode(out); },
+        VMOp::Div(a, b) => { out.push(0x38); a.encode(out); b.encode(out); },
+        VMOp::Mod(a, b) => { out.push(0x39); a.encode(out); b.encode(out); },
+        VMOp::IDiv(a, b) => { out.push(0x3a); a.encode(out); b.encode(out); },
+        VMOp::IMod(a, b) => { out.push(0x3b); a.encode(out); b.encode(out); },
+        VMOp::Xor(a, b) => { out.push(0x3c); a.encode(out); b.encode(out); },
+        VMOp::And(a, b) => { out.push(0x3d); a.encode(out); b.encode(out); },
+        VMOp::Or(a, b) => { out.push(0x3e); a.encode(out); b.encode(out); },
+        VMOp::Shl(a, b) => { out.push(0x3f); a.encode(out); b.encode(out); },
+        VMOp::Shr(a, b) => { out.push(0x40); a.encode(out); b.encode(out); },
+        VMOp::Rol(a, b) => { out.push(0x41); a.encode(out); b.encode(out); },
+        VMOp::Ror(a, b) => { out.push(0x42); a.encode(out); b.encode(out); },
+        VMOp::Not(a) => { out.push(0x43); a.encode(out); },
+        VMOp::Neg(a) => { out.push(0x44); a.encode(out); },
+        VMOp::Popcnt(a) => { out.push(0x45); a.encode(out); },
+        VMOp::Clz(a) => { out.push(0x46); a.encode(out); },
+        VMOp::Ctz(a) => { out.push(0x47); a.encode(out); },
+        VMOp::Bt(a, b) => { out.push(0x48); a.encode(out); b.encode(out); },
+        VMOp::Bts(a, b) => { out.push(0x49); a.encode(out); b.encode(out); },
+        VMOp::Btc(a, b) => { out.push(0x4a); a.encode(out); b.encode(out); },
+        VMOp::Bfe(a, b, c, d) => { out.push(0x4b); a.encode(out); b.encode(out); c.encode(out); d.encode(out); },
+        VMOp::Bfi(a, b, c, d) => { out.push(0x4c); a.encode(out); b.encode(out); c.encode(out); d.encode(out); },
+        VMOp::Inp(a) => { out.push(0x4d); a.encode(out); },
+        VMOp::InpLine(a, b) => { out.push(0x4e); a.encode(out); b.encode(out); },
+        VMOp::InpInt(a) => { out.push(0x4f); a.encode(out); },
+        VMOp::InpN(a, b) => { out.push(0x50); a.encode(out); b.encode(out); },
+        VMOp::Eq(a, b) => { out.push(0x51); a.encode(out); b.encode(out); },
+        VMOp::Gt(a, b) => { out.push(0x52); a.encode(out); b.encode(out); },
+        VMOp::Lt(a, b) => { out.push(0x53); a.encode(out); b.encode(out); },
+        VMOp::SGt(a, b) => { out.push(0x54); a.encode(out); b.encode(out); },
+        VMOp::SLt(a, b) => { out.push(0x55); a.encode(out); b.encode(out); },
+        VMOp::Cmp(a, b) => { out.push(0x56); a.encode(out); b.encode(out); },
+        VMOp::Jmp(a) => { out.push(0x57); a.encode(out); },
+        VMOp::JmpRel(a) => { out.push(0x58); a.encode(out); },
+        VMOp::JmpR(a) => { out.push(0x59); a.encode(out); },
+        VMOp::Je(a) => { out.push(0x5a); a.encode(out); },
+        VMOp::Jne(a) => { out.push(0x5b); a.encode(out); },
+        VMOp::Jg(a) => { out.push(0x5c); a.encode(out); },
+        VMOp::Jge(a) => { out.push(0x5d); a.encode(out); },
+        VMOp::Jl(a) => { out.push(0x5e); a.encode(out); },
+        VMOp::Jle(a) => { out.push(0x5f); a.encode(out); },
+        VMOp::Call(a) => { out.push(0x60); a.encode(out); },
+        VMOp::CallR(a) => { out.push(0x61); a.encode(out); },
+        VMOp::Ret => out.push(0x62),
+        VMOp::Int(a) => { out.push(0x63); a.encode(out); },
+        VMOp::Iret => out.push(0x64),
+        VMOp::Print(a) => { out.push(0x65); a.encode(out); },
+        VMOp::Rand(a) => { out.push(0x66); a.encode(out); },
+        VMOp::Time(a) => { out.push(0x67); a.encode(out); },
+        VMOp::Sleep(a) => { out.push(0x68); a.encode(out); },
+        VMOp::Syscall(a) => { out.push(0x69); a.encode(out); },
+        VMOp::Getenv(a, b, c) => { out.push(0x6a); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::FRead(a, b, c) => { out.push(0x6b); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::PrintInt(a) => { out.push(0x6c); a.encode(out); },
+        VMOp::PrintHex(a) => { out.push(0x6d); a.encode(out); },
+        VMOp::PrintStr(a) => { out.push(0x6e); a.encode(out); },
+        VMOp::Brk => out.push(0x6f),
+        VMOp::Nop => out.push(0x70),
+        VMOp::Halt => out.push(0x71),
+        VMOp::HaltCode(a) => { out.push(0x72); a.encode(out); },
+        VMOp::Spawn(a, b) => { out.push(0x73); a.encode(out); b.encode(out); },
+        VMOp::Yield => out.push(0x74),
+        VMOp::Join(a) => { out.push(0x75); a.encode(out); },
+        VMOp::Cas(a, b, c) => { out.push(0x76); a.encode(out); b.encode(out); c.encode(out); },
+        VMOp::LoadW(a, b) => { out.push(0x77); a.encode(out); b.encode(out); },
+        VMOp::StoreW(a, b) => { out.push(0x78); a.encode(out); b.encode(out); },
+        VMOp::Ext(a) => { out.push(0x79); a.encode(out); },
+    }
+}
+
+// Returns the instruction and how many bytes it took up
+fn decode_op(bytes: &[u8]) -> Option<(VMOp, usize)> {
+    let mut reader = OperandReader { bytes, pos: 1 };
+    let op = match bytes.first()? {
+        0x00 => VMOp::PushI(reader.read()?),
+        0x01 => VMOp::PushR(reader.read()?),
+        0x02 => VMOp::Pop(reader.read()?),
+        0x03 => VMOp::Peek(reader.read()?, reader.read()?),
+        0x04 => VMOp::Poke(reader.read()?, reader.read()?),
+        0x05 => VMOp::Dup,
+        0x06 => VMOp::Over,
+        0x07 => VMOp::Rot,
+        0x08 => VMOp::Drop,
+        0x09 => VMOp::Pusha,
+        0x0a => VMOp::Popa,
+        0x0b => VMOp::Enter(reader.read()?),
+        0x0c => VMOp::Leave,
+        0x0d => VMOp::LdLocal(reader.read()?, reader.read()?),
+        0x0e => VMOp::StLocal(reader.read()?, reader.read()?),
+        0x0f => VMOp::VAdd(reader.read()?),
+        0x10 => VMOp::VXor(reader.read()?),
+        0x11 => VMOp::VShl(reader.read()?),
+        0x12 => VMOp::Mov(reader.read()?, reader.read()?),
+        0x13 => VMOp::MovI(reader.read()?, reader.read()?),
+        0x14 => VMOp::Xchg(reader.read()?, reader.read()?),
+        0x15 => VMOp::Cmov(reader.read()?, reader.read()?),
+        0x16 => VMOp::Select(reader.read()?, reader.read()?, reader.read()?),
+        0x17 => VMOp::Load(reader.read()?, reader.read()?),
+        0x18 => VMOp::Store(reader.read()?, reader.read()?),
+        0x19 => VMOp::Memcmp(reader.read()?, reader.read()?, reader.read()?),
+        0x1a => VMOp::Memcpy(reader.read()?, reader.read()?, reader.read()?),
+        0x1b => VMOp::Memset(reader.read()?, reader.read()?, reader.read()?),
+        0x1c => VMOp::Alloc(reader.read()?),
+        0x1d => VMOp::Free(reader.read()?),
+        0x1e => VMOp::Crc32(reader.read()?, reader.read()?, reader.read()?),
+        0x1f => VMOp::Sha256(reader.read()?, reader.read()?, reader.read()?),
+        0x20 => VMOp::XteaRound(reader.read()?, reader.read()?, reader.read()?),
+        0x21 => VMOp::Rc4Init(reader.read()?, reader.read()?),
+        0x22 => VMOp::Rc4Byte(reader.read()?),
+        0x23 => VMOp::ModExp(reader.read()?, reader.read()?, reader.read()?),
+        0x24 => VMOp::FAdd(reader.read()?, reader.read()?),
+        0x25 => VMOp::FSub(reader.read()?, reader.read()?),
+        0x26 => VMOp::FMul(reader.read()?, reader.read()?),
+        0x27 => VMOp::FDiv(reader.read()?, reader.read()?),
+        0x28 => VMOp::FCmp(reader.read()?, reader.read()?),
+        0x29 => VMOp::ItoF(reader.read()?, reader.read()?),
+        0x2a => VMOp::FtoI(reader.read()?, reader.read()?),
+        0x2b => VMOp::Add(reader.read()?, reader.read()?),
+        0x2c => VMOp::AddS(reader.read()?, reader.read()?),
+        0x2d => VMOp::Sub(reader.read()?, reader.read()?),
+        0x2e => VMOp::SubS(reader.read()?, reader.read()?),
+        0x2f => VMOp::Inc(reader.read()?),
+        0x30 => VMOp::Dec(reader.read()?),
+        0x31 => VMOp::Mul(reader.read()?, reader.read()?),
+        0x32 => VMOp::MulS(reader.read()?, reader.read()?),
+        0x33 => VMOp::MulH(reader.read()?, reader.read()?),
+        0x34 => VMOp::Mac(reader.read()?, reader.read()?, reader.read()?),
+        0x35 => VMOp::MacW(reader.read()?, reader.read()?, reader.read()?),
+        0x36 => VMOp::Add128(reader.read()?, reader.read()?, reader.read()?, reader.read()?),
+        0x37 => VMOp::Mul128(reader.read()?, reader.read()?, reader.read()?, reader.read()?),
+        0x38 => VMOp::Div(reader.read()?, reader.read()?),
+        0x39 => VMOp::Mod(reader.read()?, reader.read()?),
+        0x3a => VMOp::IDiv(reader.read()?, reader.read()?),
+        0x3b => VMOp::IMod(reader.read()?, reader.read()?),
+        0x3c => VMOp::Xor(reader.read()?, reader.read()?),
+        0x3d => VMOp::And(reader.read()?, reader.read()?),
+        0x3e => VMOp::Or(reader.read()?, reader.read()?),
+        0x3f => VMOp::Shl(reader.read()?, reader.read()?),
+        0x40 => VMOp::Shr(reader.read()?, reader.read()?),
+        0x41 => VMOp::Rol(reader.read()?, reader.read()?),
+        0x42 => VMOp::Ror(reader.read()?, reader.read()?),
+        0x43 => VMOp::Not(reader.read()?),
+        0x44 => VMOp::Neg(reader.read()?),
+        0x45 => VMOp::Popcnt(reader.read()?),
+        0x46 => VMOp::Clz(reader.read()?),
+        0x47 => VMOp::Ctz(reader.read()?),
+        0x48 => VMOp::Bt(reader.read()?, reader.read()?),
+        0x49 => VMOp::Bts(reader.read()?, reader.read()?),
+        0x4a => VMOp::Btc(reader.read()?, reader.read()?),
+        0x4b => VMOp::Bfe(reader.read()?, reader.read()?, reader.read()?, reader.read()?),
+        0x4c => VMOp::Bfi(reader.read()?, reader.read()?, reader.read()?, reader.read()?),
+        0x4d => VMOp::Inp(reader.read()?),
+        0x4e => VMOp::InpLine(reader.read()?, reader.read()?),
+        0x4f => VMOp::InpInt(reader.read()?),
+        0x50 => VMOp::InpN(reader.read()?, reader.read()?),
+        0x51 => VMOp::Eq(reader.read()?, reader.read()?),
+        0x52 => VMOp::Gt(reader.read()?, reader.read()?),
+        0x53 => VMOp::Lt(reader.read()?, reader.read()?),
+        0x54 => VMOp::SGt(reader.read()?, reader.read()?),
+        0x55 => VMOp::SLt(reader.read()?, reader.read()?),
+        0x56 => VMOp::Cmp(reader.read()?, reader.read()?),
+        0x57 => VMOp::Jmp(reader.read()?),
+        0x58 => VMOp::JmpRel(reader.read()?),
+        0x59 => VMOp::JmpR(reader.read()?),
+        0x5a => VMOp::Je(reader.read()?),
+        0x5b => VMOp::Jne(reader.read()?),
+        0x5c => VMOp::Jg(reader.read()?),
+        0x5d => VMOp::Jge(reader.read()?),
+        0x5e => VMOp::Jl(reader.read()?),
+        0x5f => VMOp::Jle(reader.read()?),
+        0x60 => VMOp::Call(reader.read()?),
+        0x61 => VMOp::CallR(reader.read()?),
+        0x62 => VMOp::Ret,
+        0x63 => VMOp::Int(reader.read()?),
+        0x64 => VMOp::Iret,
+        0x65 => VMOp::Print(reader.read()?),
+        0x66 => VMOp::Rand(reader.read()?),
+        0x67 => VMOp::Time(reader.read()?),
+        0x68 => VMOp::Sleep(reader.read()?),
+        0x69 => VMOp::Syscall(reader.read()?),
+        0x6a => VMOp::Getenv(reader.read()?, reader.read()?, reader.read()?),
+        0x6b => VMOp::FRead(reader.read()?, reader.read()?, reader.read()?),
+        0x6c => VMOp::PrintInt(reader.read()?),
+        0x6d => VMOp::PrintHex(reader.read()?),
+        0x6e => VMOp::PrintStr(reader.read()?),
+        0x6f => VMOp::Brk,
+        0x70 => VMOp::Nop,
+        0x71 => VMOp::Halt,
+        0x72 => VMOp::HaltCode(reader.read()?),
+        0x73 => VMOp::Spawn(reader.read()?, reader.read()?),
+        0x74 => VMOp::Yield,
+        0x75 => VMOp::Join(reader.read()?),
+        0x76 => VMOp::Cas(reader.read()?, reader.read()?, reader.read()?),
+        0x77 => VMOp::LoadW(reader.read()?, reader.read()?),
+        0x78 => VMOp::StoreW(reader.read()?, reader.read()?),
+        0x79 => VMOp::Ext(reader.read()?),
+        _ => return None,
+    };
+    Some((op, reader.pos))
+}
+
+// A poisoned callback only means an earlier call panicked, its state is still usable
+#[cfg(feature = "std")]
+fn lock<T: ?Sized>(mutex: &Lock<T>) -> LockGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(not(feature = "std"))]
+fn lock<T: ?Sized>(lock: &Lock<T>) -> LockGuard<'_, T> {
+    while lock.locked.compare_exchange_weak(false, true, AtomicOrdering::Acquire, AtomicOrdering::Relaxed).is_err() {
+        core::hint::spin_loop();
+    }
+    LockGuard { lock }
+}
+
+#[cfg(not(feature = "std"))]
+struct Lock<T: ?Sized> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>
+}
+
+// The flag hands out at most one guard at a time, so sharing is as safe as a Mutex
+#[cfg(not(feature = "std"))]
+unsafe impl<T: ?Sized + Send> Sync for Lock<T> {}
+
+#[cfg(not(feature = "std"))]
+impl<T> Lock<T> {
+    fn new(value: T) -> Lock<T> {
+        Lock { locked: AtomicBool::new(false), value: UnsafeCell::new(value) }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+struct LockGuard<'a, T: ?Sized> {
+    lock: &'a Lock<T>
+}
+
+#[cfg(not(feature = "std"))]
+impl<T: ?Sized> core::ops::Deref for LockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T: ?Sized> core::ops::DerefMut for LockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<T: ?Sized> Drop for LockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, AtomicOrdering::Release);
+    }
+}
+
+#[cfg(feature = "std")]
+fn io_error(err: std::io::Error) -> VMError {
+    VMError::Io(err.kind())
+}
+
+#[cfg(not(feature = "std"))]
+fn io_error(kind: IoErrorKind) -> VMError {
+    VMError::Io(kind)
+}
+
+#[cfg(feature = "std")]
+fn default_reader() -> Option<InputHandle> {
+    Some(Arc::new(Lock::new(std::io::stdin())))
+}
+
+#[cfg(not(feature = "std"))]
+fn default_reader() -> Option<InputHandle> {
+    None
+}
+
+#[cfg(feature = "std")]
+fn default_writer() -> Option<OutputHandle> {
+    Some(Arc::new(Lock::new(std::io::stdout())))
+}
+
+#[cfg(not(feature = "std"))]
+fn default_writer() -> Option<OutputHandle> {
+    None
+}
+
+// Seeds Rand from the system clock, without std runs are seeded with 0 until set_seed
+#[cfg(feature = "std")]
+fn initial_seed() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(not(feature = "std"))]
+fn initial_seed() -> u64 {
+    0
+}
+
+// Compile-time check that a VM can be moved to and shared between threads
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<VM>();
+};
+
+// offset is where the undecodable instruction starts in the input
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeError {
+    pub offset: usize
+}
+
+impl core::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "invalid instruction encoding at byte {:#x}", self.offset)
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+// A whole program is just its instructions' encodings back to back
+pub fn encode(code: &[VMOp]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for op in code {
+        encode_op(op, &mut bytes);
+    }
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<VMOp>, DecodeError> {
+    let mut code = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let (op, len) = decode_op(&bytes[offset..]).ok_or(DecodeError { offset })?;
+        code.push(op);
+        offset += len;
+    }
+    Ok(code)
+}
+
+fn guarded_stack(len: usize) -> Vec<usize> {
+    let mut stack = vec![0; len + 2];
+    stack[0] = STACK_CANARY;
+    stack[len + 1] = STACK_CANARY;
+    stack
+}
+
+// Shifting every bit out yields 0 rather than wrapping the shift amount
+fn shift(val: usize, amount: usize, op: fn(usize, u32) -> Option<usize>) -> usize {
+    amount.try_into().ok().and_then(|amount| op(val, amount)).unwrap_or(0)
+}
+
+// Offset into a guest buffer, faulting rather than wrapping past the end of the address space
+fn byte_addr(base: usize, offset: usize) -> Result<usize, VMError> {
+    base.checked_add(offset).ok_or(VMError::MemoryOutOfBounds(base))
+}
+
+fn bit_mask(len: usize) -> usize {
+    if len >= usize::BITS as usize { !0 } else { (1 << len) - 1 }
+}
+
+// CRC-32/ISO-HDLC, the one used by zlib and friends
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
+];
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19
+    ];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+
+        let mut v = h;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v.rotate_right(1);
+            v[4] = v[4].wrapping_add(t1);
+            v[0] = t1.wrapping_add(t2);
+        }
+        for i in 0..8 {
+            h[i] = h[i].wrapping_add(v[i]);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for i in 0..8 {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&h[i].to_be_bytes());
+    }
+    digest
+}
+
+// Text assembler and disassembler for the syntax VMOp's Display prints: one instruction per line, operands
+// separated by commas, ; starts a comment. Immediates are decimal, 0x hex or 'c' literals
+pub mod asm {
+    use super::*;
+    #[cfg(not(feature = "std"))]
+    use alloc::string::String;
+
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum AsmErrorKind {
+        UnknownMnemonic(String),
+        InvalidOperand(String),
+        WrongOperandCount,
+        UnterminatedChar
+    }
+
+    // line is 1-based
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct AsmError {
+        pub line: usize,
+        pub kind: AsmErrorKind
+    }
+
+    impl core::fmt::Display for AsmError {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            match &self.kind {
+                AsmErrorKind::UnknownMnemonic(name) => write!(f, "line {}: unknown mnemonic {:?}", self.line, name),
+                AsmErrorKind::InvalidOperand(operand) => write!(f, "line {}: invalid operand {:?}", self.line, operand),
+                AsmErrorKind::WrongOperandCount => write!(f, "line {}: wrong number of operands", self.line),
+                AsmErrorKind::UnterminatedChar => write!(f, "line {}: unterminated character literal", self.line),
+            }
+        }
+    }
+
+    impl core::error::Error for AsmError {}
+
+    trait AsmOperand: Sized {
+        fn parse(token: &str) -> Option<Self>;
+    }
+
+    impl AsmOperand for VMReg {
+        fn parse(token: &str) -> Option<VMReg> {
+            let reg = match token {
+                "a" | "r0" => VMReg::A,
+                "b" | "r1" => VMReg::B,
+                "c" | "r2" => VMReg::C,
+                "d" | "r3" => VMReg::D,
+                "ip" => VMReg::IP,
+                "sp" => VMReg::SP,
+                "bp" => VMReg::BP,
+                "flags" => VMReg::Flags,
+                _ => {
+                    let index: u8 = token.strip_prefix('r')?.parse().ok()?;
+                    if !(4..16).contains(&index) {
+                        return None;
+                    }
+                    // r4-r15 follow Flags in the register numbering
+                    return VMReg::decode(&[index + 4]).map(|(reg, _)| reg);
+                },
+            };
+            Some(reg)
+        }
+    }
+
+    impl AsmOperand for VMFReg {
+        fn parse(token: &str) -> Option<VMFReg> {
+            let index: u8 = token.strip_prefix('f')?.parse().ok()?;
+            VMFReg::decode(&[index]).map(|(reg, _)| reg)
+        }
+    }
+
+    impl AsmOperand for usize {
+        fn parse(token: &str) -> Option<usize> {
+            if let Some(hex) = token.strip_prefix("0x") {
+                return usize::from_str_radix(hex, 16).ok();
+            }
+            if token.starts_with('\'') {
+                return parse_char(token).map(|c| c as usize);
+            }
+            token.parse().ok()
+        }
+    }
+
+    impl AsmOperand for isize {
+        fn parse(token: &str) -> Option<isize> {
+            match token.strip_prefix('-') {
+                Some(magnitude) => usize::parse(magnitude).and_then(|val| 0isize.checked_sub_unsigned(val)),
+                None => usize::parse(token).and_then(|val| val.try_into().ok()),
+            }
+        }
+    }
+
+    impl AsmOperand for u16 {
+        fn parse(token: &str) -> Option<u16> {
+            usize::parse(token).and_then(|val| val.try_into().ok())
+        }
+    }
+
+    fn parse_char(token: &str) -> Option<char> {
+        let inner = token.strip_prefix('\'')?.strip_suffix('\'')?;
+        let mut chars = inner.chars();
+        let c = match chars.next()? {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                '0' => '\0',
+                c @ ('\\' | '\'') => c,
+                _ => return None,
+            },
+            c => c,
+        };
+        chars.next().is_none().then_some(c)
+    }
+
+    struct Operands<'a> {
+        tokens: Vec<&'a str>,
+        pos: usize,
+        error: Option<AsmErrorKind>
+    }
+
+    impl Operands<'_> {
+        fn next<T: AsmOperand>(&mut self) -> Option<T> {
+            let token = match self.tokens.get(self.pos) {
+                Some(token) => *token,
+                None => {
+                    self.error = Some(AsmErrorKind::WrongOperandCount);
+                    return None;
+                },
+            };
+            self.pos += 1;
+            let operand = T::parse(token);
+            if operand.is_none() {
+                self.error = Some(AsmErrorKind::InvalidOperand(token.into()));
+            }
+            operand
+        }
+    }
+
+    // Strips the comment and splits the rest on commas, neither counts inside a char literal
+    fn split_line(line: &str) -> Result<(&str, Vec<&str>), AsmErrorKind> {
+        let (mut commas, mut end) = (Vec::new(), line.len());
+        let (mut in_char, mut escaped) = (false, false);
+        for (i, c) in line.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_char => escaped = true,
+                '\'' => in_char = !in_char,
+                ';' if !in_char => {
+                    end = i;
+                    break;
+                },
+                ',' if !in_char => commas.push(i),
+                _ => {},
+            }
+        }
+        if in_char {
+            return Err(AsmErrorKind::UnterminatedChar);
+        }
+        let code = line[..end].trim_start();
+        let mnemonic_len = code.find(|c: char| c.is_whitespace() || c == ',').unwrap_or(code.len());
+        let mut start = end - code.len() + mnemonic_len;
+        let mut operands = Vec::new();
+        for comma in commas.into_iter().chain(Some(end)) {
+            operands.push(line[start..comma].trim());
+            start = comma + 1;
+        }
+        if operands == [""] {
+            operands.clear();
+        }
+        Ok((&code[..mnemonic_len], operands))
+    }
+
+    fn parse_line(line: &str) -> Result<Option<VMOp>, AsmErrorKind> {
+        let (mnemonic, tokens) = split_line(line)?;
+        if mnemonic.is_empty() && tokens.is_empty() {
+            return Ok(None);
+        }
+        let operand_count = tokens.len();
+        let mut operands = Operands { tokens, pos: 0, error: None };
+        let op = parse_op(&mnemonic.to_lowercase(), &mut operands);
+        if let Some(err) = operands.error {
+            return Err(err);
+        }
+        match op {
+            Some(op) if operands.pos == operand_count => Ok(Some(op)),
+            Some(_) => Err(AsmErrorKind::WrongOperandCount),
+            None => Err(AsmErrorKind::UnknownMnemonic(mnemonic.into())),
+        }
+    }
+
+    fn parse_op(mnemonic: &str, operands: &mut Operands) -> Option<VMOp> {
+        let op = match mnemonic {
+            "pushi" => VMOp::PushI(operands.next()?),
+            "pushr" => VMOp::PushR(operands.next()?),
+            "pop" => VMOp::Pop(operands.next()?),
+            "peek" => VMOp::Peek(operands.next()?, operands.next()?),
+            "poke" => VMOp::Poke(operands.next()?, operands.next()?),
+            "dup" => VMOp::Dup,
+            "over" => VMOp::Over,
+            "rot" => VMOp::Rot,
+            "drop" => VMOp::Drop,
+            "pusha" => VMOp::Pusha,
+            "popa" => VMOp::Popa,
+            "enter" => VMOp::Enter(operands.next()?),
+            "leave" => VMOp::Leave,
+            "ldlocal" => VMOp::LdLocal(operands.next()?, operands.next()?),
+            "stlocal" => VMOp::StLocal(operands.next()?, operands.next()?),
+            "vadd" => VMOp::VAdd(operands.next()?),
+            "vxor" => VMOp::VXor(operands.next()?),
+            "vshl" => VMOp::VShl(operands.next()?),
+            "mov" => VMOp::Mov(operands.next()?, operands.next()?),
+            "movi" => VMOp::MovI(operands.next()?, operands.next()?),
+            "xchg" => VMOp::Xchg(operands.next()?, operands.next()?),
+            "cmov" => VMOp::Cmov(operands.next()?, operands.next()?),
+            "select" => VMOp::Select(operands.next()?, operands.next()?, operands.next()?),
+            "load" => VMOp::Load(operands.next()?, operands.next()?),
+            "store" => VMOp::Store(operands.next()?, operands.next()?),
+            "loadw" => VMOp::LoadW(operands.next()?, operands.next()?),
+            "storew" => VMOp::StoreW(operands.next()?, operands.next()?),
+            "memcmp" => VMOp::Memcmp(operands.next()?, operands.next()?, operands.next()?),
+            "memcpy" => VMOp::Memcpy(operands.next()?, operands.next()?, operands.next()?),
+            "memset" => VMOp::Memset(operands.next()?, operands.next()?, operands.next()?),
+            "alloc" => VMOp::Alloc(operands.next()?),
+            "free" => VMOp::Free(operands.next()?),
+            "crc32" => VMOp::Crc32(operands.next()?, operands.next()?, operands.next()?),
+            "sha256" => VMOp::Sha256(operands.next()?, operands.next()?, operands.next()?),
+            "xtearound" => VMOp::XteaRound(operands.next()?, operands.next()?, operands.next()?),
+            "rc4init" => VMOp::Rc4Init(operands.next()?, operands.next()?),
+            "rc4byte" => VMOp::Rc4Byte(operands.next()?),
+            "modexp" => VMOp::ModExp(operands.next()?, operands.next()?, operands.next()?),
+            "fadd" => VMOp::FAdd(operands.next()?, operands.next()?),
+            "fsub" => VMOp::FSub(operands.next()?, operands.next()?),
+            "fmul" => VMOp::FMul(operands.next()?, operands.next()?),
+            "fdiv" => VMOp::FDiv(operands.next()?, operands.next()?),
+            "fcmp" => VMOp::FCmp(operands.next()?, operands.next()?),
+            "itof" => VMOp::ItoF(operands.next()?, operands.next()?),
+            "ftoi" => VMOp::FtoI(operands.next()?, operands.next()?),
+            "add" => VMOp::Add(operands.next()?, operands.next()?),
+            "adds" => VMOp::AddS(operands.next()?, operands.next()?),
+            "sub" => VMOp::Sub(operands.next()?, operands.next()?),
+            "subs" => VMOp::SubS(operands.next()?, operands.next()?),
+            "inc" => VMOp::Inc(operands.next()?),
+            "dec" => VMOp::Dec(operands.next()?),
+            "mul" => VMOp::Mul(operands.next()?, operands.next()?),
+            "muls" => VMOp::MulS(operands.next()?, operands.next()?),
+            "mulh" => VMOp::MulH(operands.next()?, operands.next()?),
+            "mac" => VMOp::Mac(operands.next()?, operands.next()?, operands.next()?),
+            "macw" => VMOp::MacW(operands.next()?, operands.next()?, operands.next()?),
+            "add128" => VMOp::Add128(operands.next()?, operands.next()?, operands.next()?, operands.next()?),
+            "mul128" => VMOp::Mul128(operands.next()?, operands.next()?, operands.next()?, operands.next()?),
+            "div" => VMOp::Div(operands.next()?, operands.next()?),
+            "mod" => VMOp::Mod(operands.next()?, operands.next()?),
+            "idiv" => VMOp::IDiv(operands.next()?, operands.next()?),
+            "imod" => VMOp::IMod(operands.next()?, operands.next()?),
+            "xor" => VMOp::Xor(operands.next()?, operands.next()?),
+            "and" => VMOp::And(operands.next()?, operands.next()?),
+            "or" => VMOp::Or(operands.next()?, operands.next()?),
+            "shl" => VMOp::Shl(operands.next()?, operands.next()?),
+            "shr" => VMOp::Shr(operands.next()?, operands.next()?),
+            "rol" => VMOp::Rol(operands.next()?, operands.next()?),
+            "ror" => VMOp::Ror(operands.next()?, operands.next()?),
+            "not" => VMOp::Not(operands.next()?),
+            "neg" => VMOp::Neg(operands.next()?),
+            "popcnt" => VMOp::Popcnt(operands.next()?),
+            "clz" => VMOp::Clz(operands.next()?),
+            "ctz" => VMOp::Ctz(operands.next()?),
+            "bt" => VMOp::Bt(operands.next()?, operands.next()?),
+            "bts" => VMOp::Bts(operands.next()?, operands.next()?),
+            "btc" => VMOp::Btc(operands.next()?, operands.next()?),
+            "bfe" => VMOp::Bfe(operands.next()?, operands.next()?, operands.next()?, operands.next()?),
+            "bfi" => VMOp::Bfi(operands.next()?, operands.next()?, operands.next()?, operands.next()?),
+            "inp" => VMOp::Inp(operands.next()?),
+            "inpline" => VMOp::InpLine(operands.next()?, operands.next()?),
+            "inpint" => VMOp::InpInt(operands.next()?),
+            "inpn" => VMOp::InpN(operands.next()?, operands.next()?),
+            "eq" => VMOp::Eq(operands.next()?, operands.next()?),
+            "gt" => VMOp::Gt(operands.next()?, operands.next()?),
+            "lt" => VMOp::Lt(operands.next()?, operands.next()?),
+            "sgt" => VMOp::SGt(operands.next()?, operands.next()?),
+            "slt" => VMOp::SLt(operands.next()?, operands.next()?),
+            "cmp" => VMOp::Cmp(operands.next()?, operands.next()?),
+            "jmp" => VMOp::Jmp(operands.next()?),
+            "jmprel" => VMOp::JmpRel(operands.next()?),
+            "jmpr" => VMOp::JmpR(operands.next()?),
+            "je" => VMOp::Je(operands.next()?),
+            "jne" => VMOp::Jne(operands.next()?),
+            "jg" => VMOp::Jg(operands.next()?),
+            "jge" => VMOp::Jge(operands.next()?),
+            "jl" => VMOp::Jl(operands.next()?),
+            "jle" => VMOp::Jle(operands.next()?),
+            "call" => VMOp::Call(operands.next()?),
+            "callr" => VMOp::CallR(operands.next()?),
+            "ret" => VMOp::Ret,
+            "int" => VMOp::Int(operands.next()?),
+            "iret" => VMOp::Iret,
+            "print" => VMOp::Print(operands.next()?),
+            "rand" => VMOp::Rand(operands.next()?),
+            "time" => VMOp::Time(operands.next()?),
+            "sleep" => VMOp::Sleep(operands.next()?),
+            "syscall" => VMOp::Syscall(operands.next()?),
+            "ext" => VMOp::Ext(operands.next()?),
+            "spawn" => VMOp::Spawn(operands.next()?, operands.next()?),
+            "yield" => VMOp::Yield,
+            "join" => VMOp::Join(operands.next()?),
+            "cas" => VMOp::Cas(operands.next()?, operands.next()?, operands.next()?),
+            "getenv" => VMOp::Getenv(operands.next()?, operands.next()?, operands.next()?),
+            "fread" => VMOp::FRead(operands.next()?, operands.next()?, operands.next()?),
+            "printint" => VMOp::PrintInt(operands.next()?),
+            "printhex" => VMOp::PrintHex(operands.next()?),
+            "printstr" => VMOp::PrintStr(operands.next()?),
+            "brk" => VMOp::Brk,
+            "nop" => VMOp::Nop,
+            "halt" => VMOp::Halt,
+            "haltcode" => VMOp::HaltCode(operands.next()?),
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    pub fn parse(source: &str) -> Result<Vec<VMOp>, AsmError> {
+        let mut code = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            match parse_line(line) {
+                Ok(Some(op)) => code.push(op),
+                Ok(None) => {},
+                Err(kind) => return Err(AsmError { line: i + 1, kind }),
+            }
+        }
+        Ok(code)
+    }
+
+    // One instruction per line with its address as a trailing comment, so parse reads the
+    // listing back into the same program
+    pub fn disasm(code: &[VMOp]) -> String {
+        let mut listing = String::new();
+        for (addr, op) in code.iter().enumerate() {
+            listing.push_str(&format!("{:<32}; {:#06x}\n", op.to_string(), addr));
+        }
+        listing
+    }
+}
+
+// C interface, built as a shared library for include/rusty_bustacean.h with
+// `cargo rustc --lib --release --features ffi --crate-type cdylib`.
+// Pointers must come from vm_new and not be used after vm_free, buffers must be valid for
+// their length
+#[cfg(feature = "ffi")]
+#[allow(clippy::missing_safety_doc)]
+pub mod ffi {
+    use super::*;
+
+    // Output is captured for vm_read_output and input only comes from vm_feed_input
+    pub struct VmHandle {
+        vm: VM
+    }
+
+    pub const VM_CONTINUE: i32 = 0;
+    pub const VM_HALTED: i32 = 1;
+    pub const VM_NEEDS_INPUT: i32 = 2;
+    pub const VM_ERROR: i32 = -1;
+
+    #[no_mangle]
+    pub extern "C" fn vm_new() -> *mut VmHandle {
+        let mut vm = VM::new(Vec::new());
+        vm.set_capture_output(true);
+        vm.set_nonblocking_input(true);
+        Box::into_raw(Box::new(VmHandle { vm }))
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn vm_free(handle: *mut VmHandle) {
+        if !handle.is_null() {
+            drop(Box::from_raw(handle));
+        }
+    }
+
+    // Replaces the program with encoded bytecode and resets the machine
+    #[no_mangle]
+    pub unsafe extern "C" fn vm_load(handle: *mut VmHandle, code: *const u8, len: usize) -> i32 {
+        let (handle, code) = match (handle.as_mut(), bytes(code, len)) {
+            (Some(handle), Some(code)) => (handle, code),
+            _ => return VM_ERROR,
+        };
+        match decode(code) {
+            Ok(code) => {
+                handle.vm.code = code;
+                handle.vm.reset();
+                VM_CONTINUE
+            },
+            Err(_) => VM_ERROR,
+        }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn vm_step(handle: *mut VmHandle) -> i32 {
+        match handle.as_mut().map(|handle| handle.vm.step()) {
+            Some(Ok(StepOutcome::Continue)) => VM_CONTINUE,
+            Some(Ok(StepOutcome::Halted(_))) => VM_HALTED,
+            Some(Ok(StepOutcome::NeedsInput)) => VM_NEEDS_INPUT,
+            _ => VM_ERROR,
+        }
+    }
+
+    // Registers use their bytecode numbering, unknown registers read as 0
+    #[no_mangle]
+    pub unsafe extern "C" fn vm_get_reg(handle: *const VmHandle, reg: u8) -> usize {
+        match (handle.as_ref(), VMReg::decode(&[reg])) {
+            (Some(handle), Some((reg, _))) => handle.vm.reg(reg),
+            _ => 0,
+        }
+    }
+
+    #[no_mangle]
+    pub unsafe extern "C" fn vm_feed_input(handle: *mut VmHandle, input: *const u8, len: usize) -> i32 {
+        match (handle.as_mut(), bytes(input, len)) {
+            (Some(handle), Some(input)) => {
+                handle.vm.push_input(input);
+                VM_CONTINUE
+            },
+            _ => VM_ERROR,
+        }
+    }
+
+    // Moves up to cap bytes of pending output into buf and returns how many were copied
+    #[no_mangle]
+    pub unsafe extern "C" fn vm_read_output(handle: *mut VmHandle, buf: *mut u8, cap: usize) -> usize {
+        let handle = match handle.as_mut() {
+            Some(handle) if !buf.is_null() => handle,
+            _ => return 0,
+        };
+        let output = handle.vm.captured_output.get_or_insert_with(Vec::new);
+        let count = output.len().min(cap);
+        std::slice::from_raw_parts_mut(buf, count).copy_from_slice(&output[..count]);
+        output.drain(..count);
+        count
+    }
+
+    unsafe fn bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+        match len {
+            0 => Some(&[]),
+            _ if ptr.is_null() => None,
+            _ => Some(std::slice::from_raw_parts(ptr, len)),
+        }
+    }
+}
+
+// Browser bindings, meant for no_std builds since wasm32-unknown-unknown has no clock or stdio.
+// Output is handed to a JS callback after each step and input is pushed in with feed_input
+#[cfg(feature = "wasm")]
+pub mod wasm {
+    use super::*;
+    use wasm_bindgen::prelude::*;
+
+    #[wasm_bindgen]
+    pub struct WasmVm {
+        vm: VM,
+        on_output: Option<js_sys::Function>
+    }
+
+    #[wasm_bindgen]
+    impl WasmVm {
+        #[wasm_bindgen(constructor)]
+        pub fn new() -> WasmVm {
+            let mut vm = VM::new(Vec::new());
+            vm.set_capture_output(true);
+            vm.set_nonblocking_input(true);
+            WasmVm { vm, on_output: None }
+        }
+
+        // Replaces the program with encoded bytecode and resets the machine
+        pub fn load(&mut self, code: &[u8]) -> Result<(), JsValue> {
+            self.vm.code = decode(code).map_err(|err| JsValue::from_str(&err.to_string()))?;
+            self.vm.reset();
+            Ok(())
+        }
+
+        pub fn feed_input(&mut self, input: &[u8]) {
+            self.vm.push_input(input);
+        }
+
+        // Called with a Uint8Array of whatever the program printed
+        pub fn on_output(&mut self, callback: js_sys::Function) {
+            self.on_output = Some(callback);
+        }
+
+        // Returns 0 to continue, 1 once halted and 2 when waiting for input
+        pub fn step(&mut self) -> Result<i32, JsValue> {
+            let outcome = self.vm.step();
+            self.flush_output()?;
+            match outcome.map_err(|err| JsValue::from_str(&err.to_string()))? {
+                StepOutcome::Continue => Ok(0),
+                StepOutcome::Halted(_) => Ok(1),
+                StepOutcome::NeedsInput => Ok(2),
+            }
+        }
+
+        // Steps until the program halts, needs input or max_steps run out
+        pub fn run(&mut self, max_steps: usize) -> Result<i32, JsValue> {
+            for _ in 0..max_steps {
+                match self.step()? {
+                    0 => {},
+                    status => return Ok(status),
+                }
+            }
+            Ok(0)
+        }
+
+        pub fn reg(&self, reg: u8) -> usize {
+            VMReg::decode(&[reg]).map(|(reg, _)| self.vm.reg(reg)).unwrap_or(0)
+        }
+
+        fn flush_output(&mut self) -> Result<(), JsValue> {
+            let bytes = self.vm.take_output();
+            match &self.on_output {
+                Some(callback) if !bytes.is_empty() => {
+                    callback.call1(&JsValue::NULL, &js_sys::Uint8Array::from(&bytes[..]))?;
+                    Ok(())
+                },
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Default for WasmVm {
+        fn default() -> WasmVm {
+            WasmVm::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use VMOp::*;
+    use VMReg::*;
+
+    // Runs setup followed by op on an 8-bit VM and returns the outcome with the VM
+    fn run_8bit(mode: OverflowMode, setup: &[VMOp], op: VMOp) -> (Result<ExitStatus, VMError>, VM) {
+        let mut code = setup.to_vec();
+        code.extend_from_slice(&[op, Halt]);
+        let mut vm = VMBuilder::new().word_size(8).overflow_mode(mode).build(code).unwrap();
+        (vm.run(), vm)
+    }
+
+    // Each case overflows an 8-bit word and should land on the given value when wrapping
+    fn overflow_cases() -> Vec<(Vec<VMOp>, VMOp, VMReg, usize)> {
+        vec![
+            (vec![MovI(A, 0xff), MovI(B, 1)], Add(A, B), A, 0),
+            (vec![MovI(A, 0), MovI(B, 1)], Sub(A, B), A, 0xff),
+            (vec![MovI(A, 0x10), MovI(B, 0x11)], Mul(A, B), A, 0x10),
+            (vec![MovI(A, 0xff)], Inc(A), A, 0),
+            (vec![MovI(A, 0)], Dec(A), A, 0xff),
+            (vec![MovI(D, 1), MovI(A, 0x10), MovI(B, 0x10)], Mac(D, A, B), D, 1),
+            (vec![MovI(D, 0xff), MovI(A, 1), MovI(B, 1)], Mac(D, A, B), D, 0),
+        ]
+    }
+
+    #[test]
+    fn wrapping_mode_wraps_at_word_size() {
+        for (setup, op, reg, expected) in overflow_cases() {
+            let (result, vm) = run_8bit(OverflowMode::Wrapping, &setup, op);
+            assert!(result.is_ok(), "{} failed: {:?}", op, result);
+            assert_eq!(vm.reg(reg), expected, "{}", op);
+        }
+    }
+
+    #[test]
+    fn checked_mode_faults_on_overflow() {
+        for (setup, op, _, _) in overflow_cases() {
+            let (result, _) = run_8bit(OverflowMode::Checked, &setup, op);
+            assert!(matches!(result, Err(VMError::ArithmeticOverflow)), "{} gave {:?}", op, result);
+        }
+    }
+
+    #[test]
+    fn checked_mode_allows_results_that_fit() {
+        let cases = vec![
+            (vec![MovI(A, 0xfe), MovI(B, 1)], Add(A, B), A, 0xff),
+            (vec![MovI(A, 1), MovI(B, 1)], Sub(A, B), A, 0),
+            (vec![MovI(A, 0xf), MovI(B, 0x11)], Mul(A, B), A, 0xff),
+            (vec![MovI(A, 0xfe)], Inc(A), A, 0xff),
+            (vec![MovI(A, 1)], Dec(A), A, 0),
+            (vec![MovI(D, 0xef), MovI(A, 4), MovI(B, 4)], Mac(D, A, B), D, 0xff),
+        ];
+        for (setup, op, reg, expected) in cases {
+            let (result, vm) = run_8bit(OverflowMode::Checked, &setup, op);
+            assert!(result.is_ok(), "{} failed: {:?}", op, result);
+            assert_eq!(vm.reg(reg), expected, "{}", op);
+        }
+    }
+
+    #[test]
+    fn trap_mode_sets_the_overflow_flag() {
+        let (result, vm) = run_8bit(OverflowMode::Trap(None), &[MovI(A, 0xff)], Inc(A));
+        assert!(result.is_ok());
+        assert_eq!(vm.reg(A), 0);
+        assert_ne!(vm.reg(Flags) & FLAG_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn trap_mode_runs_the_handler_and_returns() {
+        let code = vec![MovI(A, 0xff), Inc(A), MovI(C, 1), Halt, MovI(D, 9), Iret];
+        let mut vm = VMBuilder::new().word_size(8).overflow_mode(OverflowMode::Trap(Some(4))).build(code).unwrap();
+        vm.run().unwrap();
+        assert_eq!((vm.reg(A), vm.reg(C), vm.reg(D)), (0, 1, 9));
+        assert_ne!(vm.reg(Flags) & FLAG_OVERFLOW, 0);
+        assert!(vm.stack().is_empty());
+    }
+
+    // Forks share the syscall handler's Arc<Lock<..>>, so every thread contends on it
+    #[test]
+    fn parallel_vms_share_host_callbacks() {
+        const THREADS: usize = 64;
+        const ROUNDS: usize = 200;
+
+        let calls = Arc::new(Lock::new(0));
+        let program = vec![
+            MovI(C, ROUNDS),
+            Rand(A),
+            Print(A),
+            Syscall(1),
+            Dec(C),
+            MovI(B, 0),
+            Cmp(C, B),
+            Jne(1),
+            Halt,
+        ];
+        let mut base = VMBuilder::new().seed(0x1337).capture_output(true).build(program).unwrap();
+        let counter = Arc::clone(&calls);
+        base.register_syscall(1, move |_| *lock(&counter) += 1);
+
+        let reference = {
+            let mut vm = base.fork();
+            vm.run().unwrap();
+            vm.take_output()
+        };
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let mut vm = base.fork();
+                std::thread::spawn(move || {
+                    vm.run().unwrap();
+                    vm.take_output()
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), reference);
+        }
+        assert_eq!(String::from_utf8_lossy(&reference).chars().count(), ROUNDS);
+        assert_eq!(*lock(&calls), (THREADS + 1) * ROUNDS);
+    }
+
+    #[test]
+    fn rot_stays_inside_the_stack() {
+        let mut vm = VM::new(vec![PushI(1), PushI(2), PushI(3), Rot, Halt]);
+        vm.run().unwrap();
+        assert_eq!(vm.stack(), &[2, 3, 1]);
+
+        for sp in [DEFAULT_STACK_SIZE + 1, DEFAULT_STACK_SIZE + 2] {
+            let mut vm = VM::new(vec![MovI(SP, sp), Rot, Halt]);
+            assert!(matches!(vm.run(), Err(VMError::StackOverflow)), "sp {:#x}", sp);
+            assert_eq!(vm.stack[DEFAULT_STACK_SIZE + 1], STACK_CANARY);
+        }
+    }
+
+    #[test]
+    fn sleep_scale_is_validated() {
+        let mut vm = VM::new(vec![]);
+        assert!(matches!(vm.set_sleep_scale(-1.0), Err(VMError::InvalidSleepScale)));
+        assert!(matches!(vm.set_sleep_scale(f64::NAN), Err(VMError::InvalidSleepScale)));
+        assert!(matches!(vm.set_sleep_scale(f64::INFINITY), Err(VMError::InvalidSleepScale)));
+        assert!(vm.set_sleep_scale(0.0).is_ok());
+    }
+
+    #[test]
+    fn huge_sleeps_saturate_at_the_deadline() {
+        let mut vm = VM::new(vec![MovI(A, usize::MAX), Sleep(A), Halt]);
+        vm.set_sleep_scale(4096.0).unwrap();
+        vm.set_deadline(Duration::from_millis(1));
+        assert!(matches!(vm.run(), Err(VMError::DeadlineExceeded)));
+    }
+
+    fn run_vector(mode: OverflowMode, op: VMOp) -> (Result<ExitStatus, VMError>, VM) {
+        let code = vec![PushI(0xff), PushI(3), PushI(1), PushI(70), op, Halt];
+        let mut vm = VMBuilder::new().word_size(8).overflow_mode(mode).build(code).unwrap();
+        (vm.run(), vm)
+    }
+
+    #[test]
+    fn vector_ops_follow_word_size_and_overflow_mode() {
+        let (result, vm) = run_vector(OverflowMode::Wrapping, VAdd(2));
+        assert!(result.is_ok());
+        assert_eq!(vm.stack(), &[0, 0x49]);
+
+        let (result, vm) = run_vector(OverflowMode::Checked, VAdd(2));
+        assert!(matches!(result, Err(VMError::ArithmeticOverflow)));
+        assert_eq!(vm.stack(), &[0xff, 3, 1, 70]);
+
+        let (result, vm) = run_vector(OverflowMode::Trap(None), VAdd(2));
+        assert!(result.is_ok());
+        assert_eq!(vm.stack(), &[0, 0x49]);
+        assert_ne!(vm.reg(Flags) & FLAG_OVERFLOW, 0);
+
+        let (result, vm) = run_vector(OverflowMode::Checked, VShl(2));
+        assert!(result.is_ok());
+        assert_eq!(vm.stack(), &[0xfe, 0]);
+
+        let (result, vm) = run_vector(OverflowMode::Wrapping, VXor(2));
+        assert!(result.is_ok());
+        assert_eq!(vm.stack(), &[0xfe, 0x45]);
+    }
+
+    #[test]
+    fn vector_ops_reject_huge_widths() {
+        let mut vm = VM::new(vec![PushI(1), VAdd(usize::MAX / 2 + 1), Halt]);
+        assert!(matches!(vm.run(), Err(VMError::StackUnderflow)));
+    }
+
+    #[test]
+    fn locals_live_inside_the_frame() {
+        let mut vm = VM::new(vec![Enter(2), MovI(A, 5), StLocal(A, 1), LdLocal(B, 1), Leave, Halt]);
+        vm.run().unwrap();
+        assert_eq!(vm.reg(B), 5);
+        assert!(vm.stack().is_empty());
+
+        let mut vm = VM::new(vec![MovI(BP, usize::MAX), LdLocal(A, 1), Halt]);
+        assert!(matches!(vm.run(), Err(VMError::StackOverflow)));
+    }
+
+    #[test]
+    fn registers_are_masked_to_the_word_size() {
+        let code = vec![MovI(A, 0x1234), MovI(B, 0xff), Not(B), Halt];
+        let mut vm = VMBuilder::new().word_size(8).build(code).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.reg(A), 0x34);
+        assert_eq!(vm.reg(B), 0);
+
+        let mut vm = VM::new(vec![MovI(A, 0x1234)]);
+        vm.set_word_size(8).unwrap();
+        assert_eq!(vm.reg(A), 0);
+        vm.set_register(A, 0x1ff);
+        assert_eq!(vm.reg(A), 0xff);
+    }
+
+    #[test]
+    fn saturating_ops_clamp_at_the_word_size() {
+        let cases = vec![
+            (vec![MovI(A, 0xff), MovI(B, 1)], AddS(A, B), 0xff),
+            (vec![MovI(A, 0x80), MovI(B, 2)], MulS(A, B), 0xff),
+            (vec![MovI(A, 0x7f), MovI(B, 2)], MulS(A, B), 0xfe),
+            (vec![MovI(A, 1), MovI(B, 2)], SubS(A, B), 0),
+        ];
+        for (setup, op, expected) in cases {
+            let (result, vm) = run_8bit(OverflowMode::Checked, &setup, op);
+            assert!(result.is_ok(), "{} failed: {:?}", op, result);
+            assert_eq!(vm.reg(A), expected, "{}", op);
+        }
+    }
+
+    #[test]
+    fn clz_counts_within_the_word() {
+        let (result, vm) = run_8bit(OverflowMode::Wrapping, &[MovI(A, 1)], Clz(A));
+        assert!(result.is_ok());
+        assert_eq!(vm.reg(A), 7);
+
+        let (result, vm) = run_8bit(OverflowMode::Wrapping, &[MovI(SP, 0x100)], Clz(SP));
+        assert!(result.is_ok());
+        assert_eq!(vm.reg(SP), 8);
+    }
+
+    fn counting_program() -> Vec<VMOp> {
+        vec![MovI(C, 10), PushR(C), MovI(A, 0x41), Store(C, A), Dec(C), MovI(B, 0), Cmp(C, B), Jne(1), Halt]
+    }
+
+    #[test]
+    fn snapshot_restore_round_trips() {
+        let mut vm = VM::new(counting_program());
+        assert!(matches!(vm.run_with_limit(12), Err(VMError::OutOfFuel)));
+        let state = vm.snapshot();
+        let paused = vm.clone();
+        vm.run().unwrap();
+        let finished = vm.clone();
+
+        vm.restore(&state).unwrap();
+        assert!(vm == paused);
+        vm.run().unwrap();
+        assert!(vm == finished);
+        assert_eq!(vm.stack(), &[10, 9, 8, 7, 6, 5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn restore_rejects_states_that_dont_fit() {
+        let mut vm = VM::new(counting_program());
+        let state = vm.snapshot();
+
+        let mut bad = state.clone();
+        bad.memory.truncate(16);
+        assert!(matches!(vm.restore(&bad), Err(VMError::MemoryOutOfBounds(16))));
+
+        let mut bad = state.clone();
+        bad.stack = vec![0; bad.stack_capacity + 1];
+        assert!(matches!(vm.restore(&bad), Err(VMError::StackOverflow)));
+
+        let mut bad = state.clone();
+        bad.stack_capacity = usize::MAX;
+        assert!(matches!(vm.restore(&bad), Err(VMError::StackOverflow)));
+        assert!(vm.restore(&state).is_ok());
+    }
+
+    #[test]
+    fn snapshots_hold_only_live_stack_slots() {
+        let mut vm = VM::new(vec![PushI(7), Halt]);
+        vm.run().unwrap();
+        let state = vm.snapshot();
+        assert_eq!(state.stack, vec![7]);
+        assert_eq!(state.stack_capacity, DEFAULT_STACK_SIZE);
+
+        let mut other = VM::new(vec![]);
+        other.restore(&state).unwrap();
+        assert_eq!(other.stack(), &[7]);
+        assert_eq!(other.stack[0], STACK_CANARY);
+        assert_eq!(other.stack[DEFAULT_STACK_SIZE + 1], STACK_CANARY);
+    }
+
+    #[test]
+    fn join_resumes_after_the_spawned_thread_halts() {
+        let mut vm = VM::new(vec![
+            MovI(B, 0),
+            Spawn(A, 6),
+            Inc(B),
+            Join(A),
+            Halt,
+            Halt,
+            // Spawned thread: bumps memory[0] around a Yield, then halts
+            MovI(D, 0),
+            Load(C, D),
+            Inc(C),
+            Store(D, C),
+            Yield,
+            Load(C, D),
+            Inc(C),
+            Store(D, C),
+            Halt,
+        ]);
+        vm.run().unwrap();
+        assert_eq!(vm.reg(B), 1);
+        assert_eq!(vm.memory()[0], 2);
+        assert_eq!(vm.ip(), 5);
+    }
+
+    #[test]
+    fn yield_alternates_between_threads() {
+        let mut vm = VM::new(vec![
+            Spawn(A, 5),
+            Yield,
+            Join(A),
+            PrintInt(B),
+            Halt,
+            MovI(C, 0x61),
+            Print(C),
+            Yield,
+            Print(C),
+            Halt,
+        ]);
+        vm.set_capture_output(true);
+        vm.run().unwrap();
+        assert_eq!(vm.take_output(), b"aa0");
+    }
+
+    #[test]
+    fn reset_replays_runs_bit_for_bit() {
+        let code = vec![Rand(A), Inp(B), Rand(C), InpInt(D), Time(R4), Halt];
+        let mut vm = VMBuilder::new().deterministic(42).input("x123\n").build(code.clone()).unwrap();
+        vm.run().unwrap();
+        let first = vm.clone();
+        vm.reset();
+        vm.run().unwrap();
+        assert!(vm == first);
+        assert_eq!(vm.reg(B), b'x' as usize);
+        assert_eq!(vm.reg(D), 123);
+
+        let mut other = VMBuilder::new().deterministic(42).input("x123\n").build(code).unwrap();
+        other.run().unwrap();
+        assert!(other == first);
+    }
+
+    #[test]
+    fn reset_drops_pushed_input() {
+        let mut vm = VM::new(vec![Inp(A), Halt]);
+        vm.set_nonblocking_input(true);
+        vm.push_input(b"a");
+        vm.run().unwrap();
+        vm.reset();
+        assert_eq!(vm.step().unwrap(), StepOutcome::NeedsInput);
+    }
+
+    #[test]
+    fn trace_stops_at_starved_input() {
+        let mut vm = VM::new(vec![MovI(A, 1), Inp(B), Halt]);
+        vm.set_nonblocking_input(true);
+        let ips: Vec<usize> = vm.trace().map(|entry| entry.unwrap().ip).collect();
+        assert_eq!(ips, vec![0]);
+        assert_eq!(vm.ip(), 1);
+
+        vm.push_input(b"z");
+        let ips: Vec<usize> = vm.trace().map(|entry| entry.unwrap().ip).collect();
+        assert_eq!(ips, vec![1, 2]);
+        assert_eq!(vm.reg(B), b'z' as usize);
+    }
+
+    #[derive(Default)]
+    struct InputLog {
+        bytes: Vec<u8>
+    }
+
+    impl Observer for Arc<Lock<InputLog>> {
+        fn on_input(&mut self, byte: u8) {
+            lock(self).bytes.push(byte);
+        }
+    }
+
+    #[test]
+    fn starved_retries_fire_hooks_and_observers_once() {
+        let mut vm = VM::new(vec![InpN(A, 2), Halt]);
+        vm.set_nonblocking_input(true);
+        let log = Arc::new(Lock::new(InputLog::default()));
+        vm.add_observer(Arc::clone(&log));
+        let hooked = Arc::new(Lock::new(Vec::new()));
+        let hook_log = Arc::clone(&hooked);
+        vm.set_pre_hook(move |ip, _, _| lock(&hook_log).push(ip));
+
+        vm.push_input(b"a");
+        assert_eq!(vm.step().unwrap(), StepOutcome::NeedsInput);
+        assert_eq!(vm.step().unwrap(), StepOutcome::NeedsInput);
+        assert!(lock(&log).bytes.is_empty());
+        vm.push_input(b"b");
+        assert_eq!(vm.step().unwrap(), StepOutcome::Continue);
+        assert_eq!(vm.reg(A), 0x6162);
+        assert_eq!(lock(&log).bytes, b"ab");
+        assert_eq!(*lock(&hooked), vec![0]);
+    }
+
+    // Every opcode with its operand bytes all set to filler, which is a valid register too
+    fn every_variant(filler: u8) -> Vec<VMOp> {
+        (0..=u8::MAX)
+            .map_while(|opcode| {
+                let mut bytes = vec![filler; 32];
+                bytes[0] = opcode;
+                decode_op(&bytes).map(|(op, _)| op)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn disasm_round_trips_every_variant() {
+        let mut program = every_variant(1);
+        assert_eq!(program.len(), 0x7a, "opcodes are no longer contiguous");
+        program.extend(every_variant(3));
+        program.extend_from_slice(&[
+            PushI(usize::MAX),
+            JmpRel(isize::MIN),
+            JmpRel(-1),
+            Ext(u16::MAX),
+            Mov(R15, Flags),
+            FAdd(VMFReg::F3, VMFReg::F0),
+        ]);
+        assert_eq!(asm::parse(&asm::disasm(&program)).unwrap(), program);
+    }
+}
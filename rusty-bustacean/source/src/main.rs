@@ -1,364 +1,507 @@
-use std::io::Read;
+use clap::{Parser, Subcommand};
+use rusty_bustacean::{asm, decode, encode, StepOutcome, VMOp, VMReg, VM};
 
-#[derive(Clone, Copy)]
-enum VMReg {
-    A,
-    B,
-    C,
-    D,
-    IP,
-    SP
-}
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
-#[derive(Clone, Copy)]
-enum VMOp {
-    PushI(usize),
-    PushR(VMReg),
-    Pop(VMReg),
-    Add(VMReg, VMReg),
-    Sub(VMReg, VMReg),
-    Mul(VMReg, VMReg),
-    Div(VMReg, VMReg),
-    Xor(VMReg, VMReg),
-    And(VMReg, VMReg),
-    Or(VMReg, VMReg),
-    Shl(VMReg, VMReg),
-    Shr(VMReg, VMReg),
-    Inp(VMReg),
-    Eq(VMReg, VMReg),
-    Gt(VMReg, VMReg),
-    Lt(VMReg, VMReg),
-    Jmp(usize),
-    JmpRel(usize),
-    Call(usize),
-    Ret,
-    Print(VMReg),
-    Halt
+#[derive(Parser)]
+#[command(about = "Runs, assembles and inspects rusty_bustacean programs")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>
 }
 
-struct VM {
-    pub a: usize,
-    b: usize,
-    c: usize,
-    d: usize,
-    ip: usize,
-    sp: usize,
-    is_halted: bool,
-    stack: [usize; 0xff],
-    code: Vec<VMOp>
+// Programs without a path fall back to the embedded challenge
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Runs a program, exiting with its exit code")]
+    Run { program: Option<PathBuf> },
+    #[command(about = "Assembles a text program into bytecode, by default next to the source as .bin")]
+    Asm {
+        source: PathBuf,
+        #[arg(short, long)]
+        output: Option<PathBuf>
+    },
+    #[command(about = "Prints a program as assembly with its addresses")]
+    Disasm { program: Option<PathBuf> },
+    #[command(about = "Runs a program, printing every instruction and the registers it changed to stderr")]
+    Trace { program: Option<PathBuf> },
+    #[command(about = "Steps through a program at an interactive prompt, see help there")]
+    Debug { program: Option<PathBuf> }
 }
 
-impl VM {
-    pub fn new(code: Vec<VMOp>) -> VM {
-        VM {
-            a: 0,
-            b: 0,
-            c: 0,
-            d: 0,
-            ip: 0,
-            sp: 0,
-            is_halted: true,
-            stack: [0; 0xff],
-            code
-        }
+fn main() {
+    let command = Cli::parse().command.unwrap_or(Command::Run { program: None });
+    let result = match command {
+        Command::Run { program } => run(program.as_deref()),
+        Command::Asm { source, output } => assemble(&source, output),
+        Command::Disasm { program } => program_from(program.as_deref()).map(|code| print!("{}", asm::disasm(&code))),
+        Command::Trace { program } => trace(program.as_deref()),
+        Command::Debug { program } => debug(program.as_deref()),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
     }
+}
+
+fn run(program: Option<&Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut vm = VM::new(program_from(program)?);
+    let status = vm.run()?;
+    process::exit(status.code() as i32);
+}
+
+fn assemble(source: &Path, output: Option<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(source).map_err(|err| format!("{}: {}", source.display(), err))?;
+    let code = asm::parse(&text).map_err(|err| format!("{}: {}", source.display(), err))?;
+    let output = output.unwrap_or_else(|| source.with_extension("bin"));
+    fs::write(&output, encode(&code)).map_err(|err| format!("{}: {}", output.display(), err))?;
+    Ok(())
+}
 
-    pub fn run(&mut self) {
-        self.is_halted = false;
-        while !self.is_halted {
-            let inst = self.code[self.ip];
-            // println!("{:?}", inst);
-            // self.debug();
-            self.exec_inst(&inst);
-            self.ip += 1;
+fn trace(program: Option<&Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut vm = VM::new(program_from(program)?);
+    for entry in vm.trace() {
+        let entry = entry?;
+        let mut line = format!("{:#06x}  {:<24}", entry.ip, entry.op.to_string());
+        for (i, (before, after)) in entry.regs_before.iter().zip(&entry.regs_after).enumerate() {
+            if before != after {
+                line.push_str(&format!(" {}={:#x}", gpr_name(i), after));
+            }
         }
+        eprintln!("{}", line.trim_end());
     }
+    Ok(())
+}
+
+const DEBUG_HELP: &str = "\
+break <addr>        stop before the instruction at addr
+delete <addr>       remove a breakpoint
+step [n]            execute n instructions (1 by default)
+continue            run until a breakpoint, halt or fault
+regs                print the registers
+stack               print the live stack slots, top first
+mem <addr> [len]    dump len bytes of memory (64 by default)
+input <text>        feed text and a newline to the program
+quit                leave the debugger";
+
+// Input is fed by the input command rather than stdin, which the prompt reads from
+fn debug(program: Option<&Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let code = program_from(program)?;
+    let mut vm = VM::new(code.clone());
+    vm.set_nonblocking_input(true);
+    vm.set_capture_output(true);
+    let mut breakpoints = BTreeSet::new();
+    let mut stopped = false;
+    // Whether the program's output left the cursor partway through a line
+    let mut mid_line = false;
 
-    fn exec_inst(&mut self, inst: &VMOp)
-    {
-        match inst {
-            VMOp::PushI(imm) => { self.stack[self.sp] = *imm; self.sp += 1; },
-            VMOp::PushR(reg) => { self.exec_inst(&VMOp::PushI(self.get_reg(reg))) },
-            VMOp::Pop(reg) => { self.sp -= 1; self.set_reg(reg, self.stack[self.sp]); }
-            VMOp::Add(left, right) => { self.set_reg(left, self.get_reg(left) + self.get_reg(right)) },
-            VMOp::Sub(left, right) => { self.set_reg(left, self.get_reg(left) - self.get_reg(right)) },
-            VMOp::Mul(left, right) => { self.set_reg(left, self.get_reg(left) * self.get_reg(right)) },
-            VMOp::Div(left, right) => { self.set_reg(left, self.get_reg(left) / self.get_reg(right)) },
-            VMOp::Xor(left, right) => { self.set_reg(left, self.get_reg(left) ^ self.get_reg(right)) },
-            VMOp::And(left, right) => { self.set_reg(left, self.get_reg(left) & self.get_reg(right)) },
-            VMOp::Or(left, right) => { self.set_reg(left, self.get_reg(left) | self.get_reg(right)) },
-            VMOp::Shl(left, right) => { self.set_reg(left, self.get_reg(left) << self.get_reg(right)) },
-            VMOp::Shr(left, right) => { self.set_reg(left, self.get_reg(left) >> self.get_reg(right)) },
-            VMOp::Inp(reg) => { 
-                let in_char = std::io::stdin()
-                    .bytes() 
-                    .next()
-                    .and_then(|result| result.ok())
-                    .map(|byte| byte as usize)
-                    .unwrap();
-                if in_char == 0xd { 
-                    self.exec_inst(&VMOp::Inp(*reg)) 
+    show_next(&vm, &code);
+    let stdin = io::stdin();
+    loop {
+        end_line(&mut mid_line);
+        print!("(rbvm) ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => continue,
+        };
+        let arg = words.next().map(parse_num);
+        match (command, arg) {
+            ("b", Some(Some(addr))) | ("break", Some(Some(addr))) => {
+                breakpoints.insert(addr);
+            },
+            ("d", Some(Some(addr))) | ("delete", Some(Some(addr))) => {
+                if !breakpoints.remove(&addr) {
+                    println!("no breakpoint at {:#06x}", addr);
+                }
+            },
+            ("s", count) | ("step", count) if !stopped => {
+                let count = match count {
+                    Some(Some(count)) => count,
+                    Some(None) => { println!("invalid count"); continue; },
+                    None => 1,
+                };
+                for _ in 0..count {
+                    let outcome = debug_step(&mut vm, &mut mid_line);
+                    stopped = !matches!(outcome, Some(StepOutcome::Continue) | Some(StepOutcome::NeedsInput));
+                    if outcome != Some(StepOutcome::Continue) || breakpoints.contains(&vm.ip()) {
+                        break;
+                    }
+                }
+                if !stopped {
+                    end_line(&mut mid_line);
+                    show_next(&vm, &code);
+                }
+            },
+            ("c", None) | ("continue", None) if !stopped => {
+                loop {
+                    let outcome = debug_step(&mut vm, &mut mid_line);
+                    stopped = !matches!(outcome, Some(StepOutcome::Continue) | Some(StepOutcome::NeedsInput));
+                    if outcome != Some(StepOutcome::Continue) {
+                        break;
+                    }
+                    if breakpoints.contains(&vm.ip()) {
+                        end_line(&mut mid_line);
+                        println!("breakpoint at {:#06x}", vm.ip());
+                        show_next(&vm, &code);
+                        break;
+                    }
                 }
-                else {
-                    self.set_reg(reg, in_char);
+            },
+            ("s", _) | ("step", _) | ("c", None) | ("continue", None) => println!("the program is no longer running"),
+            ("r", None) | ("regs", None) => print_regs(&vm),
+            ("st", None) | ("stack", None) => {
+                for (depth, val) in vm.stack().iter().rev().enumerate() {
+                    println!("{:>4}  {:#x}", depth, val);
+                }
+            },
+            ("x", Some(Some(addr))) | ("mem", Some(Some(addr))) => {
+                match words.next().map(parse_num).unwrap_or(Some(64)) {
+                    Some(len) => dump_memory(vm.memory(), addr, len),
+                    None => println!("invalid length"),
                 }
             },
-            VMOp::Eq(left, right) => { if self.get_reg(left) != self.get_reg(right) {self.ip += 1} },
-            VMOp::Gt(left, right) => { if self.get_reg(left) <= self.get_reg(right) {self.ip += 1} },
-            VMOp::Lt(left, right) => { if self.get_reg(left) >= self.get_reg(right) {self.ip += 1} },
-            VMOp::Jmp(addr) => { self.ip = addr - 1 },
-            VMOp::JmpRel(off) => { self.ip += off - 1 },
-            VMOp::Call(addr) => { self.exec_inst(&VMOp::PushI(self.ip + 1)); self.exec_inst(&VMOp::Jmp(*addr)); },
-            VMOp::Ret => { self.sp -= 1; self.ip = self.stack[self.sp]; }
-            VMOp::Print(reg) => { print!("{}", self.get_reg(reg) as u8 as char) },
-            VMOp::Halt => self.is_halted = true,
+            ("i", _) | ("input", _) => {
+                let text = line.trim_start().split_once(char::is_whitespace).map_or("", |(_, text)| text).trim_end();
+                vm.push_input(text.as_bytes());
+                vm.push_input(b"\n");
+            },
+            ("q", None) | ("quit", None) => return Ok(()),
+            ("h", None) | ("help", None) => println!("{}", DEBUG_HELP),
+            _ => println!("unknown command, try help"),
         }
     }
+}
 
-    fn get_reg(&self, reg: &VMReg) -> usize {
-        match reg {
-            VMReg::A => self.a,
-            VMReg::B => self.b,
-            VMReg::C => self.c,
-            VMReg::D => self.d,
-            VMReg::IP => self.ip,
-            VMReg::SP => self.sp,
-        }
+// Reports anything other than a plain step, None means the program faulted
+fn debug_step(vm: &mut VM, mid_line: &mut bool) -> Option<StepOutcome> {
+    let outcome = vm.step();
+    let output = vm.take_output();
+    if let Some(&last) = output.last() {
+        print!("{}", String::from_utf8_lossy(&output));
+        *mid_line = last != b'\n';
+    }
+    if !matches!(outcome, Ok(StepOutcome::Continue)) {
+        end_line(mid_line);
     }
+    match outcome {
+        Ok(StepOutcome::Halted(status)) => println!("halted with exit code {}", status.code()),
+        Ok(StepOutcome::NeedsInput) => println!("waiting for input at {:#06x}, feed it with input", vm.ip()),
+        Ok(StepOutcome::Continue) => {},
+        Err(ref err) => println!("fault at {:#06x}: {}", vm.ip(), err),
+    }
+    outcome.ok()
+}
 
-    fn set_reg(&mut self, reg: &VMReg, val: usize) {
-        match reg {
-            VMReg::A => self.a = val,
-            VMReg::B => self.b = val,
-            VMReg::C => self.c = val,
-            VMReg::D => self.d = val,
-            VMReg::IP => self.ip = val,
-            VMReg::SP => self.sp = val,
-        }
+fn end_line(mid_line: &mut bool) {
+    if *mid_line {
+        println!();
+        *mid_line = false;
     }
 }
 
-fn main() {
+fn show_next(vm: &VM, code: &[VMOp]) {
+    match code.get(vm.ip()) {
+        Some(op) => println!("{:#06x}  {}", vm.ip(), op),
+        None => println!("{:#06x}  <outside the program>", vm.ip()),
+    }
+}
+
+fn print_regs(vm: &VM) {
+    use VMReg::*;
+
+    let regs = [A, B, C, D, R4, R5, R6, R7, R8, R9, R10, R11, R12, R13, R14, R15, IP, SP, BP, Flags];
+    for row in regs.chunks(4) {
+        let row: Vec<String> = row.iter().map(|reg| format!("{:>5}={:<#18x}", reg.to_string(), vm.reg(*reg))).collect();
+        println!("{}", row.join(" ").trim_end());
+    }
+}
+
+// 16 bytes a row, with the printable ones alongside
+fn dump_memory(memory: &[u8], addr: usize, len: usize) {
+    let end = addr.saturating_add(len).min(memory.len());
+    if addr >= end {
+        println!("{:#x} is outside memory ({:#x} bytes)", addr, memory.len());
+        return;
+    }
+    for (row, bytes) in memory[addr..end].chunks(16).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = bytes.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+        println!("{:#06x}  {:<47}  {}", addr + row * 16, hex.join(" "), text);
+    }
+}
+
+fn parse_num(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn gpr_name(index: usize) -> String {
+    match index {
+        0 => VMReg::A.to_string(),
+        1 => VMReg::B.to_string(),
+        2 => VMReg::C.to_string(),
+        3 => VMReg::D.to_string(),
+        _ => format!("r{}", index),
+    }
+}
+
+fn program_from(path: Option<&Path>) -> Result<Vec<VMOp>, Box<dyn std::error::Error>> {
+    match path {
+        Some(path) => load_program(path).map_err(|err| format!("{}: {}", path.display(), err).into()),
+        None => Ok(embedded_program()),
+    }
+}
+
+// .rbasm and .asm files are assembly text, anything else is encoded bytecode
+fn load_program(path: &Path) -> Result<Vec<VMOp>, Box<dyn std::error::Error>> {
+    let bytes = fs::read(path)?;
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("rbasm") | Some("asm") => Ok(asm::parse(std::str::from_utf8(&bytes)?)?),
+        _ => Ok(decode(&bytes)?),
+    }
+}
+
+fn embedded_program() -> Vec<VMOp> {
     use VMOp::*;
     use VMReg::*;
 
-    let mut vm = VM::new(
-        vec![
-            PushI(8),
-            Pop(A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            PushI(0xaa551337),
-            Pop(D),
-            Xor(C, D),
-            PushI(0xcc397250),
-            Pop(A),
-            Eq(A, C),
-            Jmp(33),
-            PushI('L' as usize),
-            Pop(A),
-            Print(A),
-            PushI('o' as usize),
-            Pop(A),
-            Print(A),
-            PushI('s' as usize),
-            Pop(A),
-            Print(A),
-            PushI('e' as usize),
-            Pop(A),
-            Print(A),
-            Halt,
-            PushI(0x4444),
-            Pop(A),
-            PushI(0x3759),
-            Pop(B),
-            PushI(7),
-            Pop(B),
-            Mul(A, B),
-            Or(A, B),
-            PushI(3),
-            Pop(B),
-            Shr(A, B),
-            Inp(B),
-            PushI(8),
-            Pop(C),
-            Shl(B, C),
-            Inp(D),
-            Or(B, D),
-            PushI(0x40cc),
-            Pop(C),
-            Xor(B, C),
-            Eq(A, B),
-            JmpRel(2),
-            Jmp(20),
-            PushI(8),
-            Pop(A),
-            PushI(0),
-            Inp(B),
-            Pop(C),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            PushR(C),
-            Pop(D),
-            PushI(0x561245),
-            Pop(B),
-            Mul(B, IP),
-            Shl(B, A),
-            Or(B, A),
-            Xor(B, IP),
-            PushI(0x233),
-            Pop(A),
-            Mul(B, A),
-            Sub(C, B),
-            Xor(C, D),
-            PushI(13636),
-            PushI(5492355013),
-            Pop(A),
-            Pop(B),
-            Mul(A, B),
-            Eq(A, C),
-            JmpRel(2),
-            Jmp(20),
-            Add(C, D),
-            Mul(C, B),
-            PushR(C),
-            Pop(D),
-            PushI(8),
-            Pop(A),
-            PushI(0),
-            Inp(B),
-            Pop(C),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Xor(D, C),
-            PushR(D),
-            PushI(261534559663319),
-            Pop(B),
-            PushR(B),
-            Pop(D),
-            PushI(20803),
-            Pop(A),
-            PushI(1),
-            Pop(C),
-            Eq(A, C),
-            Jmp(148),
-            Add(B, D),
-            PushR(A),
-            PushI(1),
-            Pop(A),
-            Add(C, A),
-            Pop(A),
-            Jmp(139),
-            Pop(D),
-            Eq(B, D),
-            JmpRel(2),
-            Jmp(20),
-            PushI(8),
-            Pop(A),
-            PushI(0),
-            Inp(B),
-            Pop(C),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            Shl(C, A),
-            Inp(B),
-            Or(C, B),
-            PushI(0b111111111111111111111111111111111111111),
-            Pop(D),
-            And(C, D),
-            Add(D, C),
-            PushI(0xf2656e6364),
-            Pop(B),
-            Eq(B, D),
-            JmpRel(2),
-            Jmp(20),
-            PushI(0),
-            Pop(C),
-            Inp(B),
-            Xor(C, B),
-            Inp(B),
-            Eq(C, B),
-            JmpRel(2),
-            Jmp(20),
-            Inp(C),
-            Eq(C, B),
-            JmpRel(2),
-            Jmp(20),
-            PushI(0x2e),
-            Inp(A),
-            PushI(8),
-            Pop(D),
-            Shl(A, D),
-            Add(A, C),
-            PushI(0x7d2e),
-            Pop(B),
-            Eq(A, B),
-            JmpRel(2),
-            Jmp(20),
-            PushI('W' as usize),
-            Pop(A),
-            Print(A),
-            PushI('i' as usize),
-            Pop(A),
-            Print(A),
-            PushI('n' as usize),
-            Pop(A),
-            Print(A),
-            Halt
-            // flag{whats_the_difference...}
-        ]
-    );
+    vec![
+        PushI(8),
+        Pop(A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        PushI(0xaa551337),
+        Pop(D),
+        Xor(C, D),
+        PushI(0xcc397250),
+        Pop(A),
+        Eq(A, C),
+        Jmp(33),
+        PushI('L' as usize),
+        Pop(A),
+        Print(A),
+        PushI('o' as usize),
+        Pop(A),
+        Print(A),
+        PushI('s' as usize),
+        Pop(A),
+        Print(A),
+        PushI('e' as usize),
+        Pop(A),
+        Print(A),
+        Halt,
+        PushI(0x4444),
+        Pop(A),
+        PushI(0x3759),
+        Pop(B),
+        PushI(7),
+        Pop(B),
+        Mul(A, B),
+        Or(A, B),
+        PushI(3),
+        Pop(B),
+        Shr(A, B),
+        Inp(B),
+        PushI(8),
+        Pop(C),
+        Shl(B, C),
+        Inp(D),
+        Or(B, D),
+        PushI(0x40cc),
+        Pop(C),
+        Xor(B, C),
+        Eq(A, B),
+        JmpRel(2),
+        Jmp(20),
+        PushI(8),
+        Pop(A),
+        PushI(0),
+        Inp(B),
+        Pop(C),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        PushR(C),
+        Pop(D),
+        PushI(0x561245),
+        Pop(B),
+        Mul(B, IP),
+        Shl(B, A),
+        Or(B, A),
+        Xor(B, IP),
+        PushI(0x233),
+        Pop(A),
+        Mul(B, A),
+        Sub(C, B),
+        Xor(C, D),
+        PushI(13636),
+        PushI(5492355013),
+        Pop(A),
+        Pop(B),
+        Mul(A, B),
+        Eq(A, C),
+        JmpRel(2),
+        Jmp(20),
+        Add(C, D),
+        Mul(C, B),
+        PushR(C),
+        Pop(D),
+        PushI(8),
+        Pop(A),
+        PushI(0),
+        Inp(B),
+        Pop(C),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Xor(D, C),
+        PushR(D),
+        PushI(261534559663319),
+        Pop(B),
+        PushR(B),
+        Pop(D),
+        PushI(20803),
+        Pop(A),
+        PushI(1),
+        Pop(C),
+        Eq(A, C),
+        Jmp(148),
+        Add(B, D),
+        PushR(A),
+        PushI(1),
+        Pop(A),
+        Add(C, A),
+        Pop(A),
+        Jmp(139),
+        Pop(D),
+        Eq(B, D),
+        JmpRel(2),
+        Jmp(20),
+        PushI(8),
+        Pop(A),
+        PushI(0),
+        Inp(B),
+        Pop(C),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        Shl(C, A),
+        Inp(B),
+        Or(C, B),
+        PushI(0b111111111111111111111111111111111111111),
+        Pop(D),
+        And(C, D),
+        Add(D, C),
+        PushI(0xf2656e6364),
+        Pop(B),
+        Eq(B, D),
+        JmpRel(2),
+        Jmp(20),
+        PushI(0),
+        Pop(C),
+        Inp(B),
+        Xor(C, B),
+        Inp(B),
+        Eq(C, B),
+        JmpRel(2),
+        Jmp(20),
+        Inp(C),
+        Eq(C, B),
+        JmpRel(2),
+        Jmp(20),
+        PushI(0x2e),
+        Inp(A),
+        PushI(8),
+        Pop(D),
+        Shl(A, D),
+        Add(A, C),
+        PushI(0x7d2e),
+        Pop(B),
+        Eq(A, B),
+        JmpRel(2),
+        Jmp(20),
+        PushI('W' as usize),
+        Pop(A),
+        Print(A),
+        PushI('i' as usize),
+        Pop(A),
+        Print(A),
+        PushI('n' as usize),
+        Pop(A),
+        Print(A),
+        Halt
+        // flag{whats_the_difference...}
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    vm.run();
+    #[test]
+    fn embedded_program_round_trips() {
+        let program = embedded_program();
+        assert_eq!(asm::parse(&asm::disasm(&program)).unwrap(), program);
+        assert_eq!(decode(&encode(&program)).unwrap(), program);
+    }
 }